@@ -0,0 +1,56 @@
+//! Ask questions about bag rules like the ones from day 7. For instance:
+//! cargo run --release --bin bags -- input/2020/day7.txt total "shiny gold"
+use advent_of_code_2020::day07::BagRules;
+use itertools::Itertools;
+use petgraph::Direction;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: bags <rules file> <query> [bag...]
+
+Queries:
+    containers <bag>    How many different bags can eventually contain <bag>
+    total <bag>         How many bags <bag> must contain
+    depth [<bag>]       How many levels of bags are nested inside <bag>, or inside any bag
+    paths <outer> <inner>
+                        Every chain of bags through which <inner> ends up in <outer>
+    exactly [<bag>...]  The bags which directly contain these bags, and no others
+    dot [<bag>]         The rules as a GraphViz graph, with <bag>'s contents highlighted";
+
+fn run(args: &[String]) -> Result<String, String> {
+    let (path, query, bags) = match args {
+        [path, query, bags @ ..] => (path, query.as_str(), bags),
+        _ => return Err(USAGE.to_string()),
+    };
+    let rules = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))?
+        .parse::<BagRules>()?;
+    let bags = bags.iter().map(String::as_str).collect::<Vec<_>>();
+    match (query, bags.as_slice()) {
+        ("containers", [bag]) => Ok(rules
+            .count_types_of_bags_which_can_contain(bag)?
+            .to_string()),
+        ("total", [bag]) => Ok(rules.count_bags_which_must_be_contained(bag)?.to_string()),
+        ("depth", []) => Ok(rules.max_depth().to_string()),
+        ("depth", [bag]) => Ok(rules.depth(bag)?.to_string()),
+        ("paths", [outer, inner]) => Ok(rules
+            .paths(outer, inner)?
+            .iter()
+            .map(|path| path.join(" -> "))
+            .join("\n")),
+        ("exactly", contents) => Ok(rules.containing_exactly(contents)?.join("\n")),
+        ("dot", []) => rules.to_dot(None),
+        ("dot", [bag]) => rules.to_dot(Some((bag, Direction::Outgoing))),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
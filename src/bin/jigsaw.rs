@@ -0,0 +1,53 @@
+//! Assemble a jigsaw like the one from day 20 and save the picture, to check it by eye. For instance:
+//! cargo run --release --bin jigsaw -- input/2020/day20.txt picture.ppm --borders
+use advent_of_code_2020::day20::{ExportOptions, Jigsaw, Pattern, SEA_MONSTER};
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: jigsaw <tiles file> <output file> [option...]
+
+The output is a plain PBM (black and white) file, or a plain PPM (colour) file if its name ends
+in .ppm, where the sea monsters are highlighted.
+
+Options:
+    --borders           Keep the tile borders, with a gap between tiles
+    --ids               List which tile went where in the file's comments
+    --pattern <file>    Highlight this pattern instead of the sea monsters, in a PPM file";
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (tiles_path, output_path, flags) = match args {
+        [tiles_path, output_path, flags @ ..] => (tiles_path, output_path, flags),
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut options = ExportOptions::default();
+    let mut pattern = Pattern::from(SEA_MONSTER);
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--borders" => options.borders = true,
+            "--ids" => options.tile_ids = true,
+            "--pattern" => {
+                let path = flags.next().ok_or_else(|| USAGE.to_string())?;
+                pattern = Pattern::from_file(path)
+                    .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let tiles = fs::read_to_string(tiles_path)
+        .map_err(|e| format!("Couldn't read {}: {}", tiles_path, e))?;
+    let jigsaw = Jigsaw::from(tiles.as_str());
+    if output_path.ends_with(".ppm") {
+        jigsaw.write_ppm(output_path, options, Some(&pattern))
+    } else {
+        jigsaw.write_pbm(output_path, options)
+    }
+    .map_err(|e| format!("Couldn't write {}: {}", output_path, e))
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
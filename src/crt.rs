@@ -0,0 +1,114 @@
+//! Chinese remainder theorem: find x given x ≡ residue (mod modulus) for several moduli.
+//! Moduli don't need to be coprime, and everything is u128 without intermediate overflow.
+
+/// a * b % m, even when a * b doesn't fit in a u128
+pub fn mul_mod(a: u128, b: u128, m: u128) -> u128 {
+    let (mut a, mut b) = (a % m, b % m);
+    if let Some(product) = a.checked_mul(b) {
+        return product % m;
+    }
+    // Double and add
+    let mut product = 0;
+    while b > 0 {
+        if b & 1 == 1 {
+            product = add_mod(product, a, m);
+        }
+        a = add_mod(a, a, m);
+        b >>= 1;
+    }
+    product
+}
+
+// (a + b) % m for a below m and b at most m
+fn add_mod(a: u128, b: u128, m: u128) -> u128 {
+    if a >= m - b {
+        a - (m - b)
+    } else {
+        a + b
+    }
+}
+
+/// Extended Euclid: (g, x) where g = gcd(a, m) and a * x ≡ g (mod m).
+/// The Bézout coefficient is kept modulo m, so no signed arithmetic is needed.
+pub fn extended_gcd(a: u128, m: u128) -> (u128, u128) {
+    assert!(m > 0, "Expected a positive modulus");
+    // Invariant: r ≡ a * s (mod m) for both pairs
+    let (mut r0, mut r1) = (a % m, m);
+    let (mut s0, mut s1) = (1 % m, 0);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (s0, s1) = (s1, add_mod(s0, m - mul_mod(q, s1, m), m));
+    }
+    (r0, s0)
+}
+
+/// The inverse of a modulo m, if a and m are coprime
+pub fn mod_inverse(a: u128, m: u128) -> Option<u128> {
+    match extended_gcd(a, m) {
+        (1, x) => Some(x),
+        _ => None,
+    }
+}
+
+// Combine x ≡ a1 (mod n1) and x ≡ a2 (mod n2) into a single congruence modulo lcm(n1, n2)
+fn merge((a1, n1): (u128, u128), (a2, n2): (u128, u128)) -> Option<(u128, u128)> {
+    assert!(n2 > 0, "Expected a positive modulus");
+    let (g, inverse) = extended_gcd(n1, n2);
+    // x = a1 + n1 * t, with n1 * t ≡ a2 - a1 (mod n2)
+    let difference = add_mod(a2 % n2, n2 - a1 % n2, n2);
+    if difference % g != 0 {
+        return None;
+    }
+    let reduced = n2 / g;
+    let t = mul_mod(difference / g, inverse, reduced);
+    let lcm = (n1 / g).checked_mul(n2)?;
+    // Can't overflow: n1 * t + a1 < n1 * reduced = lcm
+    Some((a1 + n1 * t, lcm))
+}
+
+/// The smallest non-negative x such that x ≡ residue (mod modulus) for every
+/// (residue, modulus), along with the period of the solutions: the lcm of the moduli.
+/// None if the congruences contradict each other, or if the lcm doesn't fit in a u128.
+pub fn solve(congruences: impl IntoIterator<Item = (u128, u128)>) -> Option<(u128, u128)> {
+    congruences.into_iter().try_fold((0, 1), merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // The largest prime below 2^128
+    const BIG: u128 = u128::MAX - 158;
+    #[test]
+    fn test_mul_mod() {
+        assert_eq!(mul_mod(6, 7, 5), 2);
+        assert_eq!(mul_mod(BIG - 1, BIG - 1, BIG), 1);
+    }
+    #[test]
+    fn test_extended_gcd() {
+        assert_eq!(extended_gcd(3, 7), (1, 5));
+        // Bézout coefficients are only unique modulo m / gcd
+        let (gcd, x) = extended_gcd(12, 18);
+        assert_eq!((gcd, 12 * x % 18), (6, 6));
+        assert_eq!(mod_inverse(4, 6), None);
+        assert_eq!(mod_inverse(BIG - 1, BIG), Some(BIG - 1));
+    }
+    #[test]
+    fn test_solve() {
+        // Non-coprime moduli
+        assert_eq!(solve(vec![(2, 4), (4, 6)]), Some((10, 12)));
+        assert_eq!(solve(vec![(1, 4), (2, 6)]), None);
+        assert_eq!(solve(vec![]), Some((0, 1)));
+        assert_eq!(solve(vec![(5, 1), (3, 1)]), Some((0, 1)));
+        // Moduli whose product doesn't fit in a u128, but whose lcm does
+        assert_eq!(
+            solve(vec![(3, 1 << 100), (3 + (1 << 100), 1 << 120)]),
+            Some((3 + (1 << 100), 1 << 120))
+        );
+        assert_eq!(
+            solve(vec![(0, 1 << 100), (0, 3 << 100)]),
+            Some((0, 3 << 100))
+        );
+        assert_eq!(solve(vec![(0, BIG), (0, BIG - 2)]), None);
+    }
+}
@@ -1,185 +1,303 @@
 use aoc_runner_derive::{aoc, aoc_generator};
 use itertools::Itertools;
-use petgraph::{algo, prelude::*};
-use std::collections::HashMap;
+use petgraph::{algo, dot::Dot, graph::EdgeReference, prelude::*};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::rc::Rc;
 use std::str::FromStr;
-use strum_macros::EnumString;
 
 #[aoc_generator(day7)]
 fn parse_input(data: &str) -> BagRules {
     BagRules::from_str(data).unwrap()
 }
 
-#[derive(Eq, PartialEq, Clone, Copy, Hash, EnumString)]
-#[strum(serialize_all = "snake_case")]
-enum Adjective {
-    Bright,
-    Clear,
-    Dark,
-    Dim,
-    Dotted,
-    Drab,
-    Dull,
-    Faded,
-    Mirrored,
-    Muted,
-    Light,
-    Pale,
-    Plaid,
-    Posh,
-    Shiny,
-    Striped,
-    Vibrant,
-    Wavy,
-}
-
-#[derive(Eq, PartialEq, Clone, Copy, Hash, EnumString)]
-#[strum(serialize_all = "snake_case")]
-enum Color {
-    Aqua,
-    Beige,
-    Black,
-    Blue,
-    Bronze,
-    Brown,
-    Chartreuse,
-    Coral,
-    Crimson,
-    Cyan,
-    Gold,
-    Gray,
-    Green,
-    Fuchsia,
-    Indigo,
-    Lavender,
-    Lime,
-    Magenta,
-    Maroon,
-    Olive,
-    Orange,
-    Plum,
-    Purple,
-    Red,
-    Salmon,
-    Silver,
-    Tan,
-    Teal,
-    Tomato,
-    Turquoise,
-    Violet,
-    White,
-    Yellow,
-}
+/// Bags are named by an adjective and a colour, like "shiny gold", but any words will do.
+/// Names are interned: each one is allocated once, and shared between the graph and its index.
+type Bag = Rc<str>;
 
-#[derive(Eq, PartialEq, Clone, Copy, Hash)]
-struct Bag {
-    adjective: Adjective,
-    color: Color,
-}
-
-impl FromStr for Bag {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (adjective, color) = s
-            .strip_suffix(" bags.")
-            .or_else(|| s.strip_suffix(" bag."))
-            .or_else(|| s.strip_suffix(" bags"))
-            .or_else(|| s.strip_suffix(" bag"))
-            .unwrap()
-            .splitn(2, ' ')
-            .collect_tuple()
-            .unwrap();
-        Ok(Bag {
-            adjective: Adjective::from_str(adjective)
-                .map_err(|e| format!("{} for {}", e, adjective))?,
-            color: Color::from_str(color).map_err(|e| format!("{} for {}", e, color))?,
-        })
-    }
+fn parse_bag_name(s: &str) -> Result<&str, String> {
+    s.strip_suffix(" bags.")
+        .or_else(|| s.strip_suffix(" bag."))
+        .or_else(|| s.strip_suffix(" bags"))
+        .or_else(|| s.strip_suffix(" bag"))
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| format!("Couldn't parse \"{}\" as a bag", s))
 }
 
-struct BagRules {
+pub struct BagRules {
     nodes: HashMap<Bag, NodeIndex>,
     graph: DiGraph<Bag, u32>,
-}
-
-fn digraph_from_weighted_edges(
-    weighted_edges: &[(Bag, Bag, u32)],
-) -> (HashMap<Bag, NodeIndex>, DiGraph<Bag, u32>) {
-    let mut graph = DiGraph::new();
-    let mut nodes = HashMap::new();
-    for (input, output, weight) in weighted_edges {
-        let input_idx = *nodes
-            .entry(input.clone())
-            .or_insert_with(|| graph.add_node(input.clone()));
-        let output_idx = *nodes
-            .entry(output.clone())
-            .or_insert_with(|| graph.add_node(output.clone()));
-        graph.add_edge(input_idx, output_idx, *weight);
-    }
-    (nodes, graph)
+    // Every bag comes before the bags it contains
+    order: Vec<NodeIndex>,
 }
 
 impl FromStr for BagRules {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let weighted_edges = s
-            .split_terminator('\n')
-            .filter(|line| !line.contains("no other bags."))
-            .map(|line| line.split(" contain ").collect_tuple().unwrap())
-            .flat_map(|(container, all_content)| {
-                all_content.split(", ").map(move |content| {
-                    let (quantity, content) = content.splitn(2, ' ').collect_tuple().unwrap();
-
-                    Ok((
-                        Bag::from_str(container)?,
-                        Bag::from_str(content)?,
-                        quantity.parse::<u32>().map_err(|_| {
-                            format!("Couldn't parse \"{}\" as a bag quantity", quantity)
-                        })?,
-                    ))
-                })
-            })
-            .collect::<Result<Vec<_>, Self::Err>>()?;
-        let (nodes, graph) = digraph_from_weighted_edges(&weighted_edges);
-        Ok(Self { nodes, graph })
+        let mut rules = Self {
+            nodes: HashMap::new(),
+            graph: DiGraph::new(),
+            order: vec![],
+        };
+        for line in s.split_terminator('\n') {
+            let (container, all_content) = line
+                .split(" contain ")
+                .collect_tuple()
+                .ok_or_else(|| format!("Couldn't parse \"{}\" as a bag rule", line))?;
+            // Bags which contain nothing are nodes too: we can still ask questions about them
+            let container = rules.intern(parse_bag_name(container)?);
+            if all_content == "no other bags." {
+                continue;
+            }
+            for content in all_content.split(", ") {
+                let (quantity, content) = content
+                    .splitn(2, ' ')
+                    .collect_tuple()
+                    .ok_or_else(|| format!("Couldn't parse \"{}\" as bag contents", content))?;
+                let quantity = quantity
+                    .parse::<u32>()
+                    .map_err(|_| format!("Couldn't parse \"{}\" as a bag quantity", quantity))?;
+                let content = rules.intern(parse_bag_name(content)?);
+                rules.graph.add_edge(container, content, quantity);
+            }
+        }
+        rules.order = algo::toposort(&rules.graph, None).map_err(|cycle| {
+            let cycle = rules.cycle_through(cycle.node_id());
+            format!(
+                "Contradictory rules: a {} bag would contain itself ({})",
+                rules.graph[cycle[0]],
+                cycle.iter().map(|node| &rules.graph[*node]).join(" -> ")
+            )
+        })?;
+        Ok(rules)
     }
 }
 
 impl BagRules {
-    fn count_types_of_bags_which_can_contain(&self, target: &Bag) -> usize {
-        let target = self.nodes[target];
-        self.graph
+    fn intern(&mut self, name: &str) -> NodeIndex {
+        if let Some(node) = self.nodes.get(name) {
+            return *node;
+        }
+        let bag = Bag::from(name);
+        let node = self.graph.add_node(bag.clone());
+        self.nodes.insert(bag, node);
+        node
+    }
+    // Shortest chain of bags from start back to itself. Start must be part of a cycle.
+    // The chain is rotated to begin with whichever of its bags came first in the rules.
+    fn cycle_through(&self, start: NodeIndex) -> Vec<NodeIndex> {
+        let mut parents = HashMap::new();
+        let mut queue = VecDeque::from(vec![start]);
+        while let Some(node) = queue.pop_front() {
+            for next in self.graph.neighbors(node) {
+                if next == start {
+                    let mut cycle = vec![start, node];
+                    while *cycle.last().unwrap() != start {
+                        cycle.push(parents[cycle.last().unwrap()]);
+                    }
+                    cycle.reverse();
+                    cycle.pop();
+                    let first = cycle.iter().position_min().unwrap();
+                    cycle.rotate_left(first);
+                    cycle.push(cycle[0]);
+                    return cycle;
+                }
+                if let Entry::Vacant(entry) = parents.entry(next) {
+                    entry.insert(node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        panic!("Expected {} to be part of a cycle", self.graph[start]);
+    }
+    fn node(&self, bag: &str) -> Result<NodeIndex, String> {
+        self.nodes
+            .get(bag)
+            .copied()
+            .ok_or_else(|| format!("Unknown bag: \"{}\"", bag))
+    }
+    // The bags reachable from a bag in at most max_depth steps, the bag itself included.
+    // Outgoing for the bags it contains, Incoming for the bags it can be in.
+    fn reachable(&self, start: NodeIndex, direction: Direction, max_depth: usize) -> Vec<bool> {
+        let mut reached = vec![false; self.graph.node_count()];
+        reached[start.index()] = true;
+        let mut queue = VecDeque::from(vec![(start, 0)]);
+        while let Some((node, depth)) = queue.pop_front() {
+            if depth == max_depth {
+                continue;
+            }
+            for next in self.graph.neighbors_directed(node, direction) {
+                if !reached[next.index()] {
+                    reached[next.index()] = true;
+                    queue.push_back((next, depth + 1));
+                }
+            }
+        }
+        reached
+    }
+    /// How many different bags can eventually contain the target bag
+    pub fn count_types_of_bags_which_can_contain(&self, target: &str) -> Result<usize, String> {
+        // Every bag we can reach by going up the graph, except the target itself
+        let containers = self.reachable(self.node(target)?, Direction::Incoming, usize::MAX);
+        Ok(containers.iter().filter(|reached| **reached).count() - 1)
+    }
+    // How many bags each bag must contain, or None if that's too many to count.
+    // In reverse topological order, so that each bag's contents are counted only once.
+    fn totals(&self) -> Vec<Option<u128>> {
+        let mut totals = vec![Some(0u128); self.graph.node_count()];
+        for node in self.order.iter().rev() {
+            totals[node.index()] = self.graph.edges(*node).try_fold(0u128, |total, edge| {
+                let inner = totals[edge.target().index()]?.checked_add(1)?;
+                total.checked_add(u128::from(*edge.weight()).checked_mul(inner)?)
+            });
+        }
+        totals
+    }
+    /// How many bags the target bag must contain, all levels of nesting included
+    pub fn count_bags_which_must_be_contained(&self, target: &str) -> Result<u128, String> {
+        self.totals()[self.node(target)?.index()]
+            .ok_or_else(|| format!("A {} bag contains too many bags to count", target))
+    }
+    // How many levels of bags each bag contains, in reverse topological order like totals
+    fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.graph.node_count()];
+        for node in self.order.iter().rev() {
+            depths[node.index()] = self
+                .graph
+                .neighbors(*node)
+                .map(|inner| depths[inner.index()] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        depths
+    }
+    /// How many levels of bags are nested inside a bag: 0 for a bag which contains nothing
+    pub fn depth(&self, bag: &str) -> Result<usize, String> {
+        Ok(self.depths()[self.node(bag)?.index()])
+    }
+    /// The deepest nesting of bags in the rules
+    pub fn max_depth(&self) -> usize {
+        self.depths().into_iter().max().unwrap_or(0)
+    }
+    /// Every chain of bags through which `inner` ends up in `outer`, outermost first, sorted
+    pub fn paths(&self, outer: &str, inner: &str) -> Result<Vec<Vec<&str>>, String> {
+        let (outer, inner) = (self.node(outer)?, self.node(inner)?);
+        // Don't explore bags which can't lead to the inner bag
+        let leads_inside = self.reachable(inner, Direction::Incoming, usize::MAX);
+        let mut paths = vec![];
+        self.extend_paths(&mut vec![outer], inner, &leads_inside, &mut paths);
+        paths.sort_unstable();
+        Ok(paths)
+    }
+    fn extend_paths<'a>(
+        &'a self,
+        path: &mut Vec<NodeIndex>,
+        inner: NodeIndex,
+        leads_inside: &[bool],
+        paths: &mut Vec<Vec<&'a str>>,
+    ) {
+        let last = *path.last().unwrap();
+        if last == inner {
+            paths.push(path.iter().map(|node| &*self.graph[*node]).collect());
+            return;
+        }
+        for next in self.graph.neighbors(last) {
+            if leads_inside[next.index()] {
+                path.push(next);
+                self.extend_paths(path, inner, leads_inside, paths);
+                path.pop();
+            }
+        }
+    }
+    /// The bags which directly contain these bags, and no others. Quantities don't matter.
+    pub fn containing_exactly(&self, contents: &[&str]) -> Result<Vec<&str>, String> {
+        let sorted_unique =
+            |nodes: Vec<NodeIndex>| nodes.into_iter().sorted().dedup().collect_vec();
+        let wanted = sorted_unique(
+            contents
+                .iter()
+                .map(|bag| self.node(bag))
+                .collect::<Result<_, _>>()?,
+        );
+        Ok(self
+            .graph
             .node_indices()
-            .filter(|node| *node != target)
-            .filter(|node| algo::has_path_connecting(&self.graph, *node, target, None))
-            .count()
+            .filter(|node| sorted_unique(self.graph.neighbors(*node).collect()) == wanted)
+            .map(|node| &*self.graph[node])
+            .collect())
     }
-    fn accumulate_edge_weights(&self, node: NodeIndex) -> usize {
-        self.graph
-            .edges_directed(node, Direction::Outgoing)
-            .map(|edge| *edge.weight() as usize * (1 + self.accumulate_edge_weights(edge.target())))
-            .sum()
+    /// The rules as a GraphViz graph, with quantities on the edges. Optionally highlight a bag and
+    /// every bag reachable from it: Outgoing for its contents, Incoming for its containers.
+    pub fn to_dot(&self, highlight: Option<(&str, Direction)>) -> Result<String, String> {
+        let highlighted = match highlight {
+            Some((bag, direction)) => self.reachable(self.node(bag)?, direction, usize::MAX),
+            None => vec![false; self.graph.node_count()],
+        };
+        Ok(format!(
+            "{}",
+            Dot::with_attr_getters(
+                &self.graph,
+                &[],
+                &|_, edge: EdgeReference<u32>| {
+                    if highlighted[edge.source().index()] && highlighted[edge.target().index()] {
+                        "color = goldenrod ".to_string()
+                    } else {
+                        String::new()
+                    }
+                },
+                &|_, (node, _)| {
+                    if highlighted[node.index()] {
+                        "style = filled fillcolor = gold ".to_string()
+                    } else {
+                        String::new()
+                    }
+                },
+            )
+        ))
     }
-    fn count_bags_which_must_be_contained(&self, target: &Bag) -> usize {
-        let target = self.nodes[target];
-        self.accumulate_edge_weights(target)
+    /// The rules restricted to the bags within `depth` levels of nesting of a bag: the ones it can
+    /// be in, and the ones it contains.
+    pub fn subgraph_around(&self, bag: &str, depth: usize) -> Result<Self, String> {
+        let node = self.node(bag)?;
+        let above = self.reachable(node, Direction::Incoming, depth);
+        let below = self.reachable(node, Direction::Outgoing, depth);
+        let graph = self.graph.filter_map(
+            |node, name| {
+                if above[node.index()] || below[node.index()] {
+                    Some(name.clone())
+                } else {
+                    None
+                }
+            },
+            |_, quantity| Some(*quantity),
+        );
+        let nodes = graph
+            .node_indices()
+            .map(|node| (graph[node].clone(), node))
+            .collect();
+        let order = algo::toposort(&graph, None).expect("Expected a subgraph of a DAG to be a DAG");
+        Ok(Self {
+            nodes,
+            graph,
+            order,
+        })
     }
 }
 
-const SHINY_GOLD: Bag = Bag {
-    adjective: Adjective::Shiny,
-    color: Color::Gold,
-};
+const SHINY_GOLD: &str = "shiny gold";
 
 #[aoc(day7, part1)]
 fn part1(bag_rules: &BagRules) -> usize {
-    bag_rules.count_types_of_bags_which_can_contain(&SHINY_GOLD)
+    bag_rules
+        .count_types_of_bags_which_can_contain(SHINY_GOLD)
+        .unwrap()
 }
 
 #[aoc(day7, part2)]
-fn part2(bag_rules: &BagRules) -> usize {
-    bag_rules.count_bags_which_must_be_contained(&SHINY_GOLD)
+fn part2(bag_rules: &BagRules) -> u128 {
+    bag_rules
+        .count_bags_which_must_be_contained(SHINY_GOLD)
+        .unwrap()
 }
 
 #[cfg(test)]
@@ -198,8 +316,170 @@ dotted black bags contain no other bags.";
     #[test]
     fn example() {
         let rules = BagRules::from_str(EXAMPLE_INPUT).unwrap();
-        assert_eq!(4, rules.count_types_of_bags_which_can_contain(&SHINY_GOLD));
-        assert_eq!(32, rules.count_bags_which_must_be_contained(&SHINY_GOLD));
+        assert_eq!(
+            Ok(4),
+            rules.count_types_of_bags_which_can_contain(SHINY_GOLD)
+        );
+        assert_eq!(Ok(32), rules.count_bags_which_must_be_contained(SHINY_GOLD));
+    }
+    #[test]
+    fn free_form_names() {
+        let rules = BagRules::from_str(
+            "sparkly ultraviolet bags contain 2 shiny gold bags, 1 bag of holding bag.
+shiny gold bags contain 3 bag of holding bags.
+bag of holding bags contain no other bags.",
+        )
+        .unwrap();
+        assert_eq!(
+            Ok(1),
+            rules.count_types_of_bags_which_can_contain(SHINY_GOLD)
+        );
+        assert_eq!(Ok(3), rules.count_bags_which_must_be_contained(SHINY_GOLD));
+        assert_eq!(
+            Ok(0),
+            rules.count_bags_which_must_be_contained("bag of holding")
+        );
+        assert_eq!(3, rules.graph.node_count());
+        assert!(BagRules::from_str("shiny gold bags contain two red bags.").is_err());
+        assert!(BagRules::from_str("shiny gold contain 2 red bags.").is_err());
+    }
+    #[test]
+    fn cycles() {
+        let cyclic = "shiny gold bags contain 1 dark red bag.
+dark red bags contain 2 dark orange bags, 1 faded blue bag.
+dark orange bags contain 3 shiny gold bags.
+faded blue bags contain no other bags.";
+        assert_eq!(
+            BagRules::from_str(cyclic).err().unwrap(),
+            "Contradictory rules: a shiny gold bag would contain itself \
+             (shiny gold -> dark red -> dark orange -> shiny gold)"
+        );
+        let matryoshka = "shiny gold bags contain 1 shiny gold bag.";
+        assert_eq!(
+            BagRules::from_str(matryoshka).err().unwrap(),
+            "Contradictory rules: a shiny gold bag would contain itself \
+             (shiny gold -> shiny gold)"
+        );
+    }
+    // Each level contains two bags which both contain the next level
+    fn diamonds(depth: usize) -> BagRules {
+        let rules = (0..depth)
+            .map(|level| {
+                format!(
+                    "level {0} bags contain 1 left {0} bag, 1 right {0} bag.
+left {0} bags contain 1 level {1} bag.
+right {0} bags contain 1 level {1} bag.",
+                    level,
+                    level + 1
+                )
+            })
+            .join("\n");
+        BagRules::from_str(&rules).unwrap()
+    }
+    #[test]
+    fn large_counts() {
+        // Visiting every path would take 2^100 steps
+        let rules = diamonds(100);
+        assert_eq!(
+            Ok(4 * ((1 << 100) - 1)),
+            rules.count_bags_which_must_be_contained("level 0")
+        );
+        assert_eq!(
+            Ok(300),
+            rules.count_types_of_bags_which_can_contain("level 100")
+        );
+        assert_eq!(
+            Err("A level 0 bag contains too many bags to count".to_string()),
+            diamonds(130).count_bags_which_must_be_contained("level 0")
+        );
+        assert_eq!(
+            Ok(4 * ((1 << 100) - 1)),
+            diamonds(130).count_bags_which_must_be_contained("level 30")
+        );
+    }
+    #[test]
+    fn dot() {
+        let rules = BagRules::from_str(EXAMPLE_INPUT).unwrap();
+        let dot = rules.to_dot(None).unwrap();
+        assert!(dot.starts_with("digraph {\n"));
+        assert!(dot.contains("[ label = \"shiny gold\" ]"));
+        assert_eq!(dot.matches(" -> ").count(), 13);
+        assert_eq!(dot.matches("label = \"2\"").count(), 3);
+        assert!(!dot.contains("color"));
+        let containers = rules
+            .to_dot(Some((SHINY_GOLD, Direction::Incoming)))
+            .unwrap();
+        assert_eq!(containers.matches("fillcolor").count(), 5);
+        assert_eq!(containers.matches("color = goldenrod").count(), 6);
+        let contents = rules
+            .to_dot(Some((SHINY_GOLD, Direction::Outgoing)))
+            .unwrap();
+        assert_eq!(contents.matches("fillcolor").count(), 5);
+        assert_eq!(contents.matches("color = goldenrod").count(), 6);
+    }
+    #[test]
+    fn subgraph() {
+        let rules = BagRules::from_str(EXAMPLE_INPUT).unwrap();
+        let around = rules.subgraph_around(SHINY_GOLD, 1).unwrap();
+        assert_eq!(around.graph.node_count(), 5);
+        assert_eq!(around.graph.edge_count(), 4);
+        assert_eq!(
+            Ok(2),
+            around.count_types_of_bags_which_can_contain(SHINY_GOLD)
+        );
+        assert_eq!(Ok(3), around.count_bags_which_must_be_contained(SHINY_GOLD));
+        assert_eq!(
+            rules
+                .subgraph_around("faded blue", 0)
+                .unwrap()
+                .graph
+                .node_count(),
+            1
+        );
+        // Everything that matters to shiny gold bags is kept
+        let rules = input();
+        let around = rules.subgraph_around(SHINY_GOLD, usize::MAX).unwrap();
+        assert!(around.graph.node_count() < rules.graph.node_count());
+        assert_eq!(part1(&around), part1(&rules));
+        assert_eq!(part2(&around), part2(&rules));
+    }
+    #[test]
+    fn queries() {
+        let rules = BagRules::from_str(EXAMPLE_INPUT).unwrap();
+        assert_eq!(
+            rules.paths("light red", SHINY_GOLD),
+            Ok(vec![
+                vec!["light red", "bright white", SHINY_GOLD],
+                vec!["light red", "muted yellow", SHINY_GOLD],
+            ])
+        );
+        assert_eq!(rules.paths("light red", "faded blue").unwrap().len(), 5);
+        assert_eq!(rules.paths("faded blue", "light red"), Ok(vec![]));
+        assert_eq!(
+            rules.paths(SHINY_GOLD, SHINY_GOLD),
+            Ok(vec![vec![SHINY_GOLD]])
+        );
+        assert_eq!(rules.depth(SHINY_GOLD), Ok(2));
+        assert_eq!(rules.depth("faded blue"), Ok(0));
+        assert_eq!(rules.max_depth(), 4);
+        assert_eq!(
+            rules.containing_exactly(&["muted yellow", "bright white"]),
+            Ok(vec!["light red", "dark orange"])
+        );
+        assert_eq!(
+            rules.containing_exactly(&["dotted black", "faded blue", "faded blue"]),
+            Ok(vec!["dark olive", "vibrant plum"])
+        );
+        assert_eq!(
+            rules.containing_exactly(&[]),
+            Ok(vec!["faded blue", "dotted black"])
+        );
+        assert_eq!(
+            rules.depth("plaid purple"),
+            Err("Unknown bag: \"plaid purple\"".to_string())
+        );
+        assert!(rules.paths(SHINY_GOLD, "plaid purple").is_err());
+        assert!(rules.containing_exactly(&["plaid purple"]).is_err());
     }
     fn input() -> BagRules {
         parse_input(include_str!("../input/2020/day7.txt"))
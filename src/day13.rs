@@ -1,9 +1,8 @@
+use crate::crt;
 use aoc_runner_derive::{aoc, aoc_generator};
-use itertools::Itertools;
-use std::iter::once;
 
 #[derive(Debug)]
-struct Input {
+pub struct Input {
     earliest: usize,
     buses: Vec<Option<usize>>,
 }
@@ -44,16 +43,65 @@ impl Input {
             .min_by(|(_, left_wait), (_, right_wait)| left_wait.cmp(right_wait))
             .unwrap()
     }
-    fn product_other_buses(&self, bus: usize) -> usize {
-        self.buses
+    /// The earliest time t at which each bus departs as many minutes after t as its position in
+    /// the list. None if no time works, which can happen when bus ids aren't coprime.
+    fn earliest_aligned(&self) -> Option<u128> {
+        let offsets = self
+            .buses
             .iter()
-            .filter_map(|b| b.and_then(|b| if b == bus { None } else { Some(b) }))
-            .product()
+            .enumerate()
+            .filter_map(|(index, bus)| bus.map(|bus| (bus, index)))
+            .collect::<Vec<_>>();
+        self.first_alignment(&offsets)
     }
-    fn complement(&self, bus: usize, product: usize, target: usize) -> usize {
-        (0..bus)
-            .find(|complement| complement * product % bus == target)
-            .unwrap()
+    fn ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.buses.iter().flatten().copied()
+    }
+    /// The first k departures of each bus at or after the given time
+    pub fn next_departures(&self, time: usize, k: usize) -> Vec<(usize, Vec<usize>)> {
+        self.ids()
+            .map(|bus| {
+                let first = time.div_ceil(bus) * bus;
+                (bus, (0..k).map(|nth| first + nth * bus).collect())
+            })
+            .collect()
+    }
+    /// The earliest time t at which each of the given buses departs at t + its offset.
+    /// Only the buses listed need to align, in any order.
+    /// None if they never do, or if one of them isn't in the timetable.
+    pub fn first_alignment(&self, offsets: &[(usize, usize)]) -> Option<u128> {
+        if !offsets
+            .iter()
+            .all(|(bus, _)| self.ids().any(|id| id == *bus))
+        {
+            return None;
+        }
+        let congruences = offsets
+            .iter()
+            .map(|(bus, offset)| (Self::target_time(*offset, *bus) as u128, *bus as u128));
+        crt::solve(congruences).map(|(time, _)| time)
+    }
+    /// Which buses depart when, as a table with a row per minute in start..=end
+    pub fn timeline(&self, start: usize, end: usize) -> String {
+        let time_width = end.to_string().len().max("time".len());
+        let headers = self
+            .ids()
+            .map(|bus| format!("bus {}", bus))
+            .collect::<Vec<_>>();
+        let mut table = format!("{:<width$}", "time", width = time_width);
+        for header in headers.iter() {
+            table.push_str(&format!("  {}", header));
+        }
+        for time in start..=end {
+            let mut row = format!("{:<width$}", time, width = time_width);
+            for (bus, header) in self.ids().zip(headers.iter()) {
+                let cell = if time % bus == 0 { "D" } else { "." };
+                row.push_str(&format!("  {:^width$}", cell, width = header.len()));
+            }
+            table.push('\n');
+            table.push_str(row.trim_end());
+        }
+        table
     }
 }
 
@@ -69,26 +117,10 @@ fn part1(timetable: &Input) -> usize {
 }
 
 #[aoc(day13, part2)]
-fn part2(timetable: &Input) -> usize {
+fn part2(timetable: &Input) -> u128 {
     timetable
-        .buses
-        .iter()
-        .enumerate()
-        .filter_map(|(index, bus)| {
-            bus.map(|bus| {
-                let target = Input::target_time(index, bus);
-                let product = timetable.product_other_buses(bus);
-                let pos_complement = timetable.complement(bus, product, target) as isize;
-                let neg_complement = pos_complement - bus as isize;
-                once(product as isize * neg_complement)
-                    .chain(once(product as isize * pos_complement))
-            })
-        })
-        .multi_cartesian_product()
-        .map(|terms| terms.iter().sum())
-        .filter(|term: &isize| *term > 0)
-        .min()
-        .unwrap() as usize
+        .earliest_aligned()
+        .expect("Expected the buses to align at some point")
 }
 #[cfg(test)]
 mod tests {
@@ -115,6 +147,38 @@ mod tests {
         assert_eq!(1202161486, part2(&parse_input("0\n1789,37,47,1889")));
     }
     #[test]
+    fn test_inconsistent_schedule() {
+        // Departures from bus 4 are always even, from bus 6 always odd a minute later
+        assert_eq!(parse_input("0\n4,6").earliest_aligned(), None);
+        assert_eq!(parse_input("0\n4,x,6").earliest_aligned(), Some(4));
+        assert_eq!(part2(&parse_input("0\n6,x,4")), 6);
+    }
+    #[test]
+    fn test_queries() {
+        let timetable = parse_input("939\n7,13,x,x,59,x,31,19");
+        let departures = timetable.next_departures(939, 2);
+        assert_eq!(departures.len(), 5);
+        assert_eq!(departures[0], (7, vec![945, 952]));
+        assert_eq!(departures[2], (59, vec![944, 1003]));
+        assert_eq!(timetable.next_departures(945, 1)[0], (7, vec![945]));
+        assert_eq!(timetable.first_alignment(&[(7, 0), (13, 1)]), Some(77));
+        assert_eq!(timetable.first_alignment(&[(13, 1), (7, 0)]), Some(77));
+        assert_eq!(timetable.first_alignment(&[]), Some(0));
+        assert_eq!(timetable.first_alignment(&[(7, 0), (11, 0)]), None);
+        assert_eq!(
+            timetable.first_alignment(&[(7, 0), (13, 1), (59, 4), (31, 6), (19, 7)]),
+            timetable.earliest_aligned()
+        );
+        assert_eq!(
+            timetable.timeline(1068780, 1068783),
+            "time     bus 7  bus 13  bus 59  bus 31  bus 19
+1068780    .      .       .       .       .
+1068781    D      .       .       .       .
+1068782    .      D       .       .       .
+1068783    .      .       .       .       ."
+        );
+    }
+    #[test]
     fn test_part1_example() {
         let input = Input::from(
             "939
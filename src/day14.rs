@@ -1,33 +1,72 @@
 use aoc_runner_derive::{aoc, aoc_generator};
+use itertools::Itertools;
 use radix_fmt;
 use rustc_hash::FxHashMap;
-use std::iter::repeat;
+use std::fmt::{self, Display, Formatter};
 
-enum Version {
-    V1,
-    V2,
+const DEFAULT_WIDTH: u32 = 36;
+const MAX_WIDTH: u32 = 128;
+// V2 writes 2^n addresses for n floating bits, so more than this won't fit in memory anyway
+const MAX_FLOATING_BITS: u32 = 24;
+
+/// All ones for the lowest `width` bits
+fn word_mask(width: u32) -> u128 {
+    assert!(
+        width > 0 && width <= MAX_WIDTH,
+        "Word width must be between 1 and {} bits, got {}",
+        MAX_WIDTH,
+        width
+    );
+    !0 >> (MAX_WIDTH - width)
 }
 
 #[derive(Debug, Clone, Copy)]
-struct Mask {
-    zeroes: u64,
-    ones: u64,
+pub struct Mask {
+    zeroes: u128,
+    ones: u128,
+    word: u128,
 }
 
-impl From<&str> for Mask {
-    fn from(s: &str) -> Self {
+impl Mask {
+    fn new(s: &str, width: u32) -> Self {
+        assert!(
+            s.len() <= width as usize,
+            "Mask {} is wider than the {} bits word",
+            s,
+            width
+        );
         // They describe it as a "mask", but it's really two masks:
-        let zeroes = !u64::from_str_radix(&format!("{:1>64}", s).replace('X', "1"), 2).unwrap();
-        let ones = u64::from_str_radix(&format!("{:0>64}", s).replace('X', "0"), 2).unwrap();
-        Self { zeroes, ones }
+        let zeroes = !u128::from_str_radix(&format!("{:1>128}", s).replace('X', "1"), 2).unwrap();
+        let ones = u128::from_str_radix(&format!("{:0>128}", s).replace('X', "0"), 2).unwrap();
+        Self {
+            zeroes,
+            ones,
+            word: word_mask(width),
+        }
     }
-}
-
-impl Mask {
-    fn apply_v1(&self, x: u64) -> u64 {
-        x & !self.zeroes | self.ones
+    fn all_floating(width: u32) -> Self {
+        Self::new(&"X".repeat(width as usize), width)
     }
-    fn floating_mask(&self) -> u64 {
+    /// The bits the mask forces to one
+    pub fn ones(&self) -> u128 {
+        self.ones
+    }
+    /// The bits the mask forces to zero, within the word
+    pub fn zeroes(&self) -> u128 {
+        self.zeroes & self.word
+    }
+    /// The bits the mask leaves alone: the X's
+    pub fn floating(&self) -> u128 {
+        self.floating_mask()
+    }
+    /// Word width in bits
+    pub fn width(&self) -> u32 {
+        self.word.count_ones()
+    }
+    fn apply_v1(&self, x: u128) -> u128 {
+        (x & !self.zeroes | self.ones) & self.word
+    }
+    fn floating_mask(&self) -> u128 {
         // ones for all unset bits
         // Example:
         // original: xxx1x0xx00011;
@@ -35,13 +74,13 @@ impl Mask {
         // !ones:    1110111111100;
         // zeroes:   1111101100011;
         // output    1110101100000
-        let floating = (!self.zeroes & !self.ones) & (!0 >> (64 - 36));
+        let floating = (!self.zeroes & !self.ones) & self.word;
         floating
     }
-    fn bits(x: usize) -> String {
+    fn bits(x: u128) -> String {
         format!("{}", radix_fmt::radix(x, 2))
     }
-    fn make_nth_mask(n: usize, floating_bits_indices: &[usize]) -> usize {
+    fn make_nth_mask(n: u128, floating_bits_indices: &[usize]) -> u128 {
         let mask = floating_bits_indices
             .iter()
             .enumerate()
@@ -52,97 +91,228 @@ impl Mask {
             .fold(0, |acc, x| acc | x);
         mask
     }
-    fn make_all_masks(&self) -> impl Iterator<Item = usize> {
-        let floating_bits = Self::bits(self.floating_mask() as usize);
+    fn make_all_masks(&self) -> impl Iterator<Item = u128> {
+        let floating_bits = Self::bits(self.floating_mask());
         let floating_bits_indices = floating_bits
             .chars()
             .rev()
             .enumerate()
             .filter_map(|(index, bit)| if bit == '1' { Some(index) } else { None })
             .collect::<Vec<_>>();
-        (0..2_usize.pow(floating_bits_indices.len() as u32))
+        (0..2_u128.pow(floating_bits_indices.len() as u32))
             .map(move |n| Self::make_nth_mask(n, &floating_bits_indices))
     }
-    fn apply_v2(&self, address: usize) -> Vec<usize> {
-        let floating_mask = self.floating_mask() as usize;
-        let ones = self.ones as usize;
-        self.make_all_masks()
+    fn apply_v2(&self, address: u128) -> Result<Vec<u128>, String> {
+        let floating_mask = self.floating_mask();
+        if floating_mask.count_ones() > MAX_FLOATING_BITS {
+            return Err(format!(
+                "Mask {} has {} floating bits, can't decode more than {}",
+                self,
+                floating_mask.count_ones(),
+                MAX_FLOATING_BITS
+            ));
+        }
+        let ones = self.ones;
+        Ok(self
+            .make_all_masks()
             .map(|mask| {
-                let with_ones = address | ones;
+                let with_ones = (address | ones) & self.word;
                 let with_cleared_floating_mask = with_ones & !floating_mask;
                 let with_this_mask = with_cleared_floating_mask | mask;
                 with_this_mask
             })
-            .collect()
+            .collect())
+    }
+}
+
+impl Display for Mask {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for bit in (0..self.word.count_ones()).rev() {
+            let c = if self.zeroes >> bit & 1 == 1 {
+                '0'
+            } else if self.ones >> bit & 1 == 1 {
+                '1'
+            } else {
+                'X'
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decides which memory writes a `mem[address] = value` instruction performs under a given mask.
+/// Implement this to try out new masking semantics, for instance:
+/// ```
+/// use advent_of_code_2020::day14::{Decoder, Mask, Program};
+///
+/// /// Like V1, but for the address
+/// struct MaskedAddress;
+///
+/// impl Decoder for MaskedAddress {
+///     fn decode(&self, mask: &Mask, address: u128, value: u128) -> Result<Vec<(u128, u128)>, String> {
+///         Ok(vec![(address & !mask.zeroes() | mask.ones(), value)])
+///     }
+/// }
+///
+/// let mut prog = Program::new("mask = 1X0\nmem[3] = 7", 3);
+/// prog.run(&MaskedAddress).unwrap();
+/// assert_eq!(prog.dump(), "mem[6] = 7\n");
+/// ```
+pub trait Decoder {
+    /// Returns the (address, value) pairs to write, in order, or why they can't be worked out
+    fn decode(&self, mask: &Mask, address: u128, value: u128) -> Result<Vec<(u128, u128)>, String>;
+}
+
+/// The mask applies to the value
+pub struct V1;
+
+impl Decoder for V1 {
+    fn decode(&self, mask: &Mask, address: u128, value: u128) -> Result<Vec<(u128, u128)>, String> {
+        Ok(vec![(address, mask.apply_v1(value))])
+    }
+}
+
+/// The mask applies to the address, with floating bits taking all possible values
+pub struct V2;
+
+impl Decoder for V2 {
+    fn decode(&self, mask: &Mask, address: u128, value: u128) -> Result<Vec<(u128, u128)>, String> {
+        Ok(mask
+            .apply_v2(address)?
+            .into_iter()
+            .map(|address| (address, value & mask.word))
+            .collect())
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 enum Op {
     UpdateMask(Mask),
-    Write(usize, u64),
+    Write(u128, u128),
 }
 
-impl From<&str> for Op {
-    fn from(s: &str) -> Self {
+impl Op {
+    fn new(s: &str, width: u32) -> Self {
         if s.starts_with("mem") {
             let (left, right) = s.split_once(" = ").unwrap();
             let address = left.replace("mem[", "").replace("]", "").parse().unwrap();
             let value = right.parse().unwrap();
             Self::Write(address, value)
         } else if s.starts_with("mask") {
-            Self::UpdateMask(s.split(" = ").nth(1).unwrap().into())
+            Self::UpdateMask(Mask::new(s.split(" = ").nth(1).unwrap(), width))
         } else {
             panic!("Unknown operation: {}", s);
         }
     }
 }
 
+/// A single memory write, remembered for inspection after the fact
+#[derive(Debug, Clone, Copy)]
+pub struct Write {
+    // Index of the instruction that caused this write
+    pub step: usize,
+    pub address: u128,
+    pub value: u128,
+    // The mask that was active when the write happened
+    pub mask: Mask,
+}
+
+impl Display for Write {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {}: mem[{}] = {} (mask = {})",
+            self.step, self.address, self.value, self.mask
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
-struct Program {
+pub struct Program {
     ops: Vec<Op>,
+    // Index of the next instruction to execute
+    pc: usize,
     mask: Mask,
-    mem: FxHashMap<usize, u64>,
+    mem: FxHashMap<u128, u128>,
+    history: Vec<Write>,
 }
 
 impl From<&str> for Program {
     fn from(s: &str) -> Self {
-        let mask = repeat('X').take(36).collect::<String>().as_str().into();
+        Self::new(s, DEFAULT_WIDTH)
+    }
+}
+
+impl Program {
+    pub fn new(s: &str, width: u32) -> Self {
+        let mask = Mask::all_floating(width);
         let ops = s
             .split_terminator('\n')
-            .map(|line| line.into())
+            .map(|line| Op::new(line, width))
             .collect::<Vec<_>>();
         Self {
             mask,
             ops,
+            pc: 0,
             mem: FxHashMap::default(),
+            history: Vec::new(),
         }
     }
-}
-
-impl Program {
-    fn run(&mut self, version: Version) {
-        for op in self.ops.clone().iter() {
-            match version {
-                Version::V1 => match op {
-                    Op::UpdateMask(m) => self.mask = *m,
-                    Op::Write(address, value) => {
-                        *self.mem.entry(*address).or_insert(0) = self.mask.apply_v1(*value);
-                    }
-                },
-                Version::V2 => match op {
-                    Op::UpdateMask(m) => self.mask = *m,
-                    Op::Write(address, value) => {
-                        self.mask.apply_v2(*address).iter().for_each(|address| {
-                            *self.mem.entry(*address).or_insert(0) = *value;
-                        })
-                    }
-                },
+    /// Execute the next instruction.
+    /// Returns the writes it performed (empty for a mask update), or None once the program is over.
+    /// If the decoder fails, the program is left as it was.
+    pub fn step(&mut self, decoder: &dyn Decoder) -> Result<Option<&[Write]>, String> {
+        let op = match self.ops.get(self.pc) {
+            Some(op) => *op,
+            None => return Ok(None),
+        };
+        let step = self.pc;
+        let first_write = self.history.len();
+        match op {
+            Op::UpdateMask(m) => self.mask = m,
+            Op::Write(address, value) => {
+                let writes = decoder
+                    .decode(&self.mask, address, value)
+                    .map_err(|e| format!("Step {}: {}", step, e))?;
+                for (address, value) in writes {
+                    *self.mem.entry(address).or_insert(0) = value;
+                    self.history.push(Write {
+                        step,
+                        address,
+                        value,
+                        mask: self.mask,
+                    });
+                }
             }
         }
+        self.pc += 1;
+        Ok(Some(&self.history[first_write..]))
     }
-    fn mem_sum(&self) -> u64 {
-        self.mem.values().sum()
+    /// Execute the remaining instructions, stopping at the first one the decoder can't handle
+    pub fn run(&mut self, decoder: &dyn Decoder) -> Result<(), String> {
+        while self.step(decoder)?.is_some() {}
+        Ok(())
+    }
+    /// The sum of all values in memory, or None if it doesn't fit in a u128
+    pub fn mem_sum(&self) -> Option<u128> {
+        self.mem
+            .values()
+            .try_fold(0_u128, |sum, &value| sum.checked_add(value))
+    }
+    /// All writes that ever touched this address, oldest first
+    pub fn history_of(&self, address: u128) -> impl Iterator<Item = &Write> {
+        self.history
+            .iter()
+            .filter(move |write| write.address == address)
+    }
+    /// The current memory contents as a table, sorted by address
+    pub fn dump(&self) -> String {
+        self.mem
+            .iter()
+            .sorted()
+            .map(|(address, value)| format!("mem[{}] = {}\n", address, value))
+            .collect()
     }
 }
 
@@ -152,17 +322,19 @@ fn parse_input(s: &str) -> Program {
 }
 
 #[aoc(day14, part1)]
-fn part1(prog: &Program) -> u64 {
+fn part1(prog: &Program) -> u128 {
     let mut prog = prog.clone();
-    prog.run(Version::V1);
+    prog.run(&V1).expect("Expected the program to run");
     prog.mem_sum()
+        .expect("Expected the memory sum to fit in a u128")
 }
 
 #[aoc(day14, part2)]
-fn part2(prog: &Program) -> u64 {
+fn part2(prog: &Program) -> u128 {
     let mut prog = prog.clone();
-    prog.run(Version::V2);
+    prog.run(&V2).expect("Expected the program to run");
     prog.mem_sum()
+        .expect("Expected the memory sum to fit in a u128")
 }
 
 #[cfg(test)]
@@ -173,10 +345,14 @@ mod tests {
     }
     #[test]
     fn test_mask() {
-        let mask: Mask = "XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X".into();
+        let mask = Mask::new("XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X", DEFAULT_WIDTH);
         assert_eq!(73, mask.apply_v1(11));
         assert_eq!(101, mask.apply_v1(101));
         assert_eq!(64, mask.apply_v1(0));
+        assert_eq!(mask.ones(), 64);
+        assert_eq!(mask.zeroes(), 2);
+        assert_eq!(mask.floating(), (1 << 36) - 1 - 64 - 2);
+        assert_eq!(mask.width(), 36);
     }
     #[test]
     fn test_part1() {
@@ -192,6 +368,59 @@ mod tests {
         )
     }
     #[test]
+    fn test_wide_word() {
+        let mut prog = Program::new(
+            "mask = 1X\nmem[0] = 340282366920938463463374607431768211455",
+            128,
+        );
+        prog.run(&V1).unwrap();
+        assert_eq!(prog.mem_sum(), Some(u128::MAX));
+        let mut prog = Program::new("mask = 1X\nmem[0] = 3", 4);
+        prog.run(&V2).unwrap();
+        // Floating bits: 3, 2 and 0, with bit 1 forced to one
+        assert_eq!(prog.mem.len(), 8);
+        assert_eq!(prog.mem_sum(), Some(24));
+    }
+    #[test]
+    fn test_overflow() {
+        // The initial mask is all X's, far too many addresses to enumerate
+        let mut prog = Program::new("mem[0] = 5\nmask = 1X\nmem[0] = 5", 128);
+        assert!(prog.step(&V2).is_err());
+        assert_eq!(prog.dump(), "");
+        assert!(prog.run(&V1).is_ok());
+        assert_eq!(prog.dump(), "mem[0] = 7\n");
+        let mut prog = Program::new(
+            "mem[0] = 340282366920938463463374607431768211455\nmem[1] = 1",
+            128,
+        );
+        prog.run(&V1).unwrap();
+        assert_eq!(prog.mem_sum(), None);
+    }
+    #[test]
+    fn test_inspection() {
+        let mut prog = parse_input(
+            "mask = XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X\nmem[8] = 11\nmem[7] = 101\nmem[8] = 0",
+        );
+        assert_eq!(prog.step(&V1).unwrap().map(|writes| writes.len()), Some(0));
+        let writes = prog.step(&V1).unwrap().unwrap();
+        assert_eq!(
+            (writes[0].step, writes[0].address, writes[0].value),
+            (1, 8, 73)
+        );
+        prog.run(&V1).unwrap();
+        assert_eq!(prog.step(&V1).map(|writes| writes.is_none()), Ok(true));
+        assert_eq!(prog.dump(), "mem[7] = 101\nmem[8] = 64\n");
+        assert_eq!(
+            prog.history_of(8)
+                .map(|w| w.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "step 1: mem[8] = 73 (mask = XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X)",
+                "step 3: mem[8] = 64 (mask = XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X)"
+            ]
+        );
+    }
+    #[test]
     fn test_part2() {
         assert_eq!(part2(&input()), 4200656704538)
     }
@@ -1,87 +1,222 @@
-use aoc_runner_derive::aoc;
-use std::iter::repeat;
+use aoc_runner_derive::{aoc, aoc_generator};
+use rustc_hash::FxHashMap;
+use std::mem;
 
-fn puzzle_input(max_size: usize) -> Game {
-    Game::from(vec![9, 6, 0, 10, 18, 2, 1], max_size)
+#[aoc_generator(day15)]
+fn parse_input(s: &str) -> Vec<u32> {
+    s.trim()
+        .split(',')
+        .map(|n| {
+            n.parse()
+                .unwrap_or_else(|_| panic!("Couldn't parse \"{}\" as a starting number", n))
+        })
+        .collect()
+}
+
+// How much memory a game may take, by default. Enough for part 2 to never touch the hash map.
+const DEFAULT_MEMORY_CEILING: usize = 256 << 20;
+
+// A key, a value and a control byte for each bucket of the hash map
+const SPARSE_BUCKET_SIZE: usize = mem::size_of::<(u32, u32)>() + 1;
+
+// How many bytes the hash map's table takes when it has room for capacity entries: it keeps a
+// power of two number of buckets, at most 7/8 full
+fn sparse_size(capacity: usize) -> usize {
+    let buckets = match capacity {
+        0 => 0,
+        1..=3 => 4,
+        4..=7 => 8,
+        _ => (capacity * 8 / 7).next_power_of_two(),
+    };
+    buckets * SPARSE_BUCKET_SIZE
 }
 
 #[derive(Debug)]
 struct Game {
     last: u32,
+    // The turn on which last was spoken, counting from 1
     turn: u32,
-    previously_seen: Vec<bool>,
-    history: Vec<u32>,
+    // When each number was last spoken, 0 for never. Small numbers, which come up all the time,
+    // are looked up in an array. The rarer big ones go in a map, which may only grow as long as
+    // both together fit under the memory ceiling.
+    dense: Vec<u32>,
+    sparse: FxHashMap<u32, u32>,
+    memory_ceiling: usize,
 }
 
 impl Game {
-    fn from(seq: Vec<u32>, max_size: usize) -> Self {
-        let mut history = repeat(0).take(max_size).collect::<Vec<_>>();
-        let mut previously_seen = repeat(false).take(max_size).collect::<Vec<_>>();
-        seq.iter()
-            .take(seq.len() - 1)
-            .enumerate()
-            .for_each(|(i, n)| {
-                previously_seen[*n as usize] = true;
-                history[*n as usize] = i as u32;
-            });
-        Self {
+    // Room for every number spoken up to the given turn, in at most memory_ceiling bytes. Half
+    // of it goes to the array, the rest to the map for bigger numbers. None if the starting
+    // numbers don't fit.
+    fn with_memory_ceiling(seq: &[u32], turns: usize, memory_ceiling: usize) -> Option<Self> {
+        assert!(!seq.is_empty(), "Expected at least one starting number");
+        assert!(
+            turns <= u32::MAX as usize,
+            "Can't play more than {} turns",
+            u32::MAX
+        );
+        // Numbers spoken after the starting ones are ages, so they're always below the turn
+        let dense_size = turns.min(memory_ceiling / 2 / mem::size_of::<u32>());
+        let mut game = Self {
             last: *seq.last().unwrap(),
             turn: seq.len() as u32,
-            previously_seen,
-            history,
+            dense: vec![0; dense_size],
+            sparse: FxHashMap::default(),
+            memory_ceiling,
+        };
+        for (i, n) in seq.iter().take(seq.len() - 1).enumerate() {
+            game.speak(*n, i as u32 + 1)?;
+        }
+        Some(game)
+    }
+    // How many bytes the array and the map take
+    fn memory_used(&self) -> usize {
+        self.dense.capacity() * mem::size_of::<u32>() + sparse_size(self.sparse.capacity())
+    }
+    // Record that n was spoken on this turn, and return when it was spoken before (0 for never).
+    // None if remembering it would take the game over its memory ceiling.
+    fn speak(&mut self, n: u32, turn: u32) -> Option<u32> {
+        if let Some(previous) = self.dense.get_mut(n as usize) {
+            return Some(mem::replace(previous, turn));
+        }
+        if let Some(previous) = self.sparse.get_mut(&n) {
+            return Some(mem::replace(previous, turn));
+        }
+        let capacity = self.sparse.capacity();
+        if self.sparse.len() == capacity {
+            // Growing moves every entry to a bigger table, so both are allocated for a while
+            let growing = sparse_size(capacity) + sparse_size(capacity + 1);
+            if self.memory_used() - sparse_size(capacity) + growing > self.memory_ceiling {
+                return None;
+            }
         }
+        self.sparse.insert(n, turn);
+        Some(0)
     }
 }
 
 impl Iterator for Game {
     type Item = u32;
     fn next(&mut self) -> Option<u32> {
-        let last = self.last as usize;
-        let next = if self.previously_seen[last] {
-            self.turn - 1 - self.history[last]
-        } else {
+        let previous = self.speak(self.last, self.turn)?;
+        let next = if previous == 0 {
             0
+        } else {
+            self.turn - previous
         };
-        self.previously_seen[last] = true;
-        self.history[last] = self.turn - 1;
-        self.turn += 1;
+        self.turn = self.turn.checked_add(1)?;
         self.last = next;
         Some(next)
     }
 }
 
+/// The number spoken on the given turn, counting from 1 for the first starting number
+fn spoken_on(seq: &[u32], turn: usize) -> u32 {
+    spoken_on_within(seq, turn, DEFAULT_MEMORY_CEILING).unwrap_or_else(|| {
+        panic!(
+            "Couldn't play {} turns in {} bytes",
+            turn, DEFAULT_MEMORY_CEILING
+        )
+    })
+}
+
+/// Same as spoken_on, using at most about memory_ceiling bytes in total: half for an array of
+/// small numbers, half for a map of the bigger ones. None if that isn't enough.
+/// A billion turns fit in a ceiling of 2 GB.
+pub fn spoken_on_within(seq: &[u32], turn: usize, memory_ceiling: usize) -> Option<u32> {
+    assert!(turn > 0, "Turns start at 1");
+    if turn <= seq.len() {
+        return Some(seq[turn - 1]);
+    }
+    Game::with_memory_ceiling(seq, turn, memory_ceiling)?.nth(turn - seq.len() - 1)
+}
+
 #[aoc(day15, part1)]
-fn part1(_: &str) -> u32 {
-    let mut game = puzzle_input(2020);
-    game.nth(2020 - 7 - 1).unwrap()
+fn part1(seq: &[u32]) -> u32 {
+    spoken_on(seq, 2020)
 }
 #[aoc(day15, part2)]
-fn part2(_: &str) -> u32 {
-    let mut game = puzzle_input(30_000_000);
-    game.nth(30_000_000 - 7 - 1).unwrap()
+fn part2(seq: &[u32]) -> u32 {
+    spoken_on(seq, 30_000_000)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    fn input() -> Vec<u32> {
+        parse_input(include_str!("../input/2020/day15.txt"))
+    }
     #[test]
     fn test_examples() {
-        assert_eq!(Some(1), Game::from(vec![1, 3, 2], 2020).nth(2020 - 3 - 1));
-        assert_eq!(Some(10), Game::from(vec![2, 1, 3], 2020).nth(2020 - 3 - 1));
-        assert_eq!(Some(27), Game::from(vec![1, 2, 3], 2020).nth(2020 - 3 - 1));
-        assert_eq!(Some(78), Game::from(vec![2, 3, 1], 2020).nth(2020 - 3 - 1));
-        assert_eq!(Some(438), Game::from(vec![3, 2, 1], 2020).nth(2020 - 3 - 1));
+        assert_eq!(1, spoken_on(&[1, 3, 2], 2020));
+        assert_eq!(10, spoken_on(&[2, 1, 3], 2020));
+        assert_eq!(27, spoken_on(&[1, 2, 3], 2020));
+        assert_eq!(78, spoken_on(&[2, 3, 1], 2020));
+        assert_eq!(438, spoken_on(&[3, 2, 1], 2020));
+        assert_eq!(1836, spoken_on(&[3, 1, 2], 2020));
+        assert_eq!(436, part1(&parse_input("0,3,6\n")));
+    }
+    #[test]
+    fn test_any_game() {
+        let spoken = (1..=10)
+            .map(|turn| spoken_on(&[0, 3, 6], turn))
+            .collect::<Vec<_>>();
+        assert_eq!(spoken, vec![0, 3, 6, 0, 3, 3, 1, 0, 4, 0]);
+        // Starting numbers bigger than the number of turns
+        assert_eq!(spoken_on(&[5000, 5000], 3), 1);
+        assert_eq!(spoken_on(&[7], 1), 7);
+        assert_eq!(spoken_on(&[7], 2), 0);
+    }
+    #[test]
+    fn test_memory_ceiling() {
+        let all_dense = spoken_on(&[0, 3, 6], 1_000_000);
+        for ceiling in [2 << 20, 3 << 20, 8 << 20].iter() {
+            assert_eq!(
+                spoken_on_within(&[0, 3, 6], 1_000_000, *ceiling),
+                Some(all_dense)
+            );
+        }
+        let mut game = Game::with_memory_ceiling(&[0, 3, 6], 10_000, 1 << 15).unwrap();
+        assert_eq!(game.dense.len(), 4096);
         assert_eq!(
-            Some(1836),
-            Game::from(vec![3, 1, 2], 2020).nth(2020 - 3 - 1)
+            game.nth(10_000 - 3 - 1),
+            Some(spoken_on(&[0, 3, 6], 10_000))
         );
+        assert!(!game.sparse.is_empty());
+        assert!(game.sparse.keys().all(|n| *n >= 4096));
+        assert!(game.memory_used() <= 1 << 15);
+        // Big starting numbers go straight to the map
+        assert_eq!(spoken_on(&[u32::MAX - 1, 1, u32::MAX - 1], 4), 2);
+        // Not enough room for the big numbers
+        assert_eq!(spoken_on_within(&[0, 3, 6], 1_000_000, 1 << 20), None);
+        assert_eq!(spoken_on_within(&[0, 3, 6], 10_000, 4000), None);
+        assert_eq!(spoken_on_within(&[0, 3, 6], 4, 0), None);
+        assert_eq!(spoken_on_within(&[0, 3, 6], 3, 0), Some(6));
+    }
+    // Peak resident memory of this process, on Linux
+    fn peak_memory() -> Option<usize> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+        let kilobytes = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+        Some(kilobytes << 10)
+    }
+    #[test]
+    #[ignore] // Takes a couple of minutes: cargo test --release day15 -- --ignored
+    fn test_billion_turns() {
+        let ceiling = 2 << 30;
+        let mut game = Game::with_memory_ceiling(&input(), 1_000_000_000, ceiling).unwrap();
+        assert_eq!(game.nth(1_000_000_000 - input().len() - 1), Some(12));
+        assert!(game.memory_used() <= ceiling);
+        if let Some(peak) = peak_memory() {
+            assert!(peak <= ceiling, "Peaked at {} bytes", peak);
+        }
     }
     #[test]
     fn test_part1() {
-        assert_eq!(part1(""), 1238)
+        assert_eq!(part1(&input()), 1238)
     }
     #[test]
     fn test_part2() {
-        assert_eq!(part2(""), 3745954)
+        assert_eq!(part2(&input()), 3745954)
     }
 }
@@ -1,189 +1,472 @@
 use aoc_runner_derive::aoc;
-use std::collections::{HashMap, VecDeque};
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+use std::convert::TryFrom;
+use std::mem;
 use std::ops::Range;
 
+/// Byte range of a token in the source expression
+type Span = Range<usize>;
+
+/// Something wrong with an expression, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ExprError {
+    fn new(message: String, span: Span) -> Self {
+        Self { message, span }
+    }
+    /// The message, followed by the source with the offending part underlined:
+    /// ```text
+    /// Division by zero in 1 / 0
+    /// 1 / (2 - 2)
+    ///   ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let column = source[..self.span.start].chars().count();
+        let width = source[self.span.clone()].chars().count().max(1);
+        format!(
+            "{}\n{}\n{}{}",
+            self.message,
+            source,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+}
+
+impl BinOp {
+    fn symbol(&self) -> char {
+        match self {
+            Self::Add => '+',
+            Self::Sub => '-',
+            Self::Mul => '*',
+            Self::Div => '/',
+            Self::Rem => '%',
+            Self::Pow => '^',
+        }
+    }
+    fn apply(&self, left: i64, right: i64) -> Result<i64, String> {
+        let result = match self {
+            Self::Add => left.checked_add(right),
+            Self::Sub => left.checked_sub(right),
+            Self::Mul => left.checked_mul(right),
+            Self::Div | Self::Rem if right == 0 => {
+                return Err(format!(
+                    "Division by zero in {} {} {}",
+                    left,
+                    self.symbol(),
+                    right
+                ))
+            }
+            Self::Div => left.checked_div(right),
+            Self::Rem => left.checked_rem(right),
+            Self::Pow => {
+                let exponent = u32::try_from(right).map_err(|_| {
+                    format!(
+                        "Unsupported exponent in {} {} {}",
+                        left,
+                        self.symbol(),
+                        right
+                    )
+                })?;
+                left.checked_pow(exponent)
+            }
+        };
+        result.ok_or_else(|| format!("Overflow in {} {} {}", left, self.symbol(), right))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Token {
     LeftParen,
     RightParen,
-    Plus,
-    Times,
-    Num(u64),
+    Op(BinOp),
+    Num(i64),
 }
 // "(5 * 5 * 4 + 8) + 6 + ((5 + 5 + 2) + (5 + 6 + 2 * 4) * 2 * 9 * 4) + 7 + 4 * (6 + (6 * 6 + 7 * 3 + 5 * 3) * (6 + 6 * 9) * (3 * 7 + 8 + 2 * 5) + 4 + 7)"
 
-fn tokenize(s: &str) -> Vec<Token> {
-    s.chars()
-        .filter(|c| *c != ' ')
-        .map(|c| match c {
+fn tokenize(s: &str) -> Result<Vec<(Token, Span)>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let token = match c {
+            c if c.is_whitespace() => continue,
             '(' => Token::LeftParen,
             ')' => Token::RightParen,
-            '+' => Token::Plus,
-            '*' => Token::Times,
-            c => Token::Num(char::to_digit(c, 10).unwrap() as u64),
-        })
-        .collect()
+            '+' => Token::Op(BinOp::Add),
+            '-' => Token::Op(BinOp::Sub),
+            '*' => Token::Op(BinOp::Mul),
+            '/' => Token::Op(BinOp::Div),
+            '%' => Token::Op(BinOp::Rem),
+            '^' => Token::Op(BinOp::Pow),
+            c if c.is_ascii_digit() => {
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    end += c.len_utf8();
+                }
+                let literal = &s[start..end];
+                Token::Num(literal.parse().map_err(|_| {
+                    ExprError::new(format!("Number too large: {}", literal), start..end)
+                })?)
+            }
+            c => {
+                return Err(ExprError::new(
+                    format!("Unexpected character: {}", c),
+                    start..end,
+                ))
+            }
+        };
+        tokens.push((token, start..end));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+/// How tightly each binary operator binds (higher binds tighter) and which way it groups
+#[derive(Debug, Clone)]
+pub struct PrecedenceTable(FxHashMap<BinOp, (u8, Assoc)>);
+
+impl PrecedenceTable {
+    pub fn new(rules: &[(BinOp, u8, Assoc)]) -> Self {
+        Self(
+            rules
+                .iter()
+                .map(|(op, precedence, assoc)| (*op, (*precedence, *assoc)))
+                .collect(),
+        )
+    }
+    /// All operators are equal: evaluate from left to right
+    pub fn flat() -> Self {
+        Self::new(&[
+            (BinOp::Add, 1, Assoc::Left),
+            (BinOp::Sub, 1, Assoc::Left),
+            (BinOp::Mul, 1, Assoc::Left),
+            (BinOp::Div, 1, Assoc::Left),
+            (BinOp::Rem, 1, Assoc::Left),
+            (BinOp::Pow, 1, Assoc::Left),
+        ])
+    }
+    /// Additions and subtractions are evaluated before multiplications and divisions
+    pub fn plus_first() -> Self {
+        Self::new(&[
+            (BinOp::Add, 2, Assoc::Left),
+            (BinOp::Sub, 2, Assoc::Left),
+            (BinOp::Mul, 1, Assoc::Left),
+            (BinOp::Div, 1, Assoc::Left),
+            (BinOp::Rem, 1, Assoc::Left),
+            (BinOp::Pow, 3, Assoc::Right),
+        ])
+    }
+    /// The convention we all learnt at school
+    pub fn times_first() -> Self {
+        Self::new(&[
+            (BinOp::Add, 1, Assoc::Left),
+            (BinOp::Sub, 1, Assoc::Left),
+            (BinOp::Mul, 2, Assoc::Left),
+            (BinOp::Div, 2, Assoc::Left),
+            (BinOp::Rem, 2, Assoc::Left),
+            (BinOp::Pow, 3, Assoc::Right),
+        ])
+    }
+    fn get(&self, op: BinOp) -> Option<(u8, Assoc)> {
+        self.0.get(&op).copied()
+    }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
-struct ParenthesisPair {
-    opens_at: usize,
-    closes_at: usize,
+/// The spans point at the operator, so that evaluation errors can be reported against it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i64),
+    Neg(Span, Box<Expr>),
+    Binary(Box<Expr>, BinOp, Span, Box<Expr>),
 }
 
-impl ParenthesisPair {
-    fn new(opens_at: usize, closes_at: usize) -> Self {
-        Self {
-            opens_at,
-            closes_at,
+impl Expr {
+    // Every node, children before their parents. Walking the tree with a stack rather than
+    // recursion lets long chains of operations, like a sum of many terms, be as deep as they like.
+    fn post_order(&self) -> Vec<&Self> {
+        let mut todo = vec![self];
+        let mut nodes = vec![];
+        while let Some(expr) = todo.pop() {
+            nodes.push(expr);
+            match expr {
+                Self::Num(_) => {}
+                Self::Neg(_, x) => todo.push(x),
+                Self::Binary(left, _, _, right) => {
+                    todo.push(left);
+                    todo.push(right);
+                }
+            }
+        }
+        nodes.reverse();
+        nodes
+    }
+    pub fn evaluate(&self) -> Result<i64, ExprError> {
+        let mut values: Vec<i64> = vec![];
+        for expr in self.post_order() {
+            let value = match expr {
+                Self::Num(x) => *x,
+                Self::Neg(span, _) => {
+                    let x = values.pop().unwrap();
+                    x.checked_neg().ok_or_else(|| {
+                        ExprError::new(format!("Overflow in -{}", x), span.clone())
+                    })?
+                }
+                Self::Binary(_, op, span, _) => {
+                    let right = values.pop().unwrap();
+                    let left = values.pop().unwrap();
+                    op.apply(left, right)
+                        .map_err(|message| ExprError::new(message, span.clone()))?
+                }
+            };
+            values.push(value);
         }
+        Ok(values.pop().unwrap())
     }
-    fn content_offset(&self) -> usize {
-        self.opens_at + 1
+    /// Reverse Polish notation, with "neg" for unary minus: 2 3 4 5 * + *
+    pub fn to_rpn(&self) -> String {
+        self.post_order()
+            .iter()
+            .map(|expr| match expr {
+                Self::Num(x) => x.to_string(),
+                Self::Neg(_, _) => "neg".to_string(),
+                Self::Binary(_, op, _, _) => op.symbol().to_string(),
+            })
+            .join(" ")
+    }
+    /// Infix, with parentheses around every operation: (2 * (3 + (4 * 5)))
+    pub fn to_parenthesised(&self) -> String {
+        // Subexpressions still to write, or text to write after them
+        enum Piece<'a> {
+            Expr(&'a Expr),
+            Text(String),
+        }
+        let mut out = String::new();
+        let mut todo = vec![Piece::Expr(self)];
+        while let Some(piece) = todo.pop() {
+            match piece {
+                Piece::Text(text) => out.push_str(&text),
+                Piece::Expr(Self::Num(x)) => out.push_str(&x.to_string()),
+                Piece::Expr(Self::Neg(_, x)) => {
+                    out.push_str("(-");
+                    todo.push(Piece::Text(")".to_string()));
+                    todo.push(Piece::Expr(x));
+                }
+                Piece::Expr(Self::Binary(left, op, _, right)) => {
+                    out.push('(');
+                    todo.push(Piece::Text(")".to_string()));
+                    todo.push(Piece::Expr(right));
+                    todo.push(Piece::Text(format!(" {} ", op.symbol())));
+                    todo.push(Piece::Expr(left));
+                }
+            }
+        }
+        out
     }
-    fn content(&self) -> Range<usize> {
-        self.content_offset()..self.closes_at
+    /// One node per line, children indented below their operator:
+    /// ```text
+    /// *
+    /// ├── 2
+    /// └── +
+    ///     ├── 3
+    ///     └── 4
+    /// ```
+    pub fn to_tree(&self) -> String {
+        let mut out = String::new();
+        // Each node, with what goes in front of it and what goes in front of its descendants
+        let mut todo = vec![(self, String::new(), String::new())];
+        while let Some((expr, first_prefix, prefix)) = todo.pop() {
+            let (label, children) = match expr {
+                Self::Num(x) => (x.to_string(), vec![]),
+                Self::Neg(_, x) => ("-".to_string(), vec![x]),
+                Self::Binary(left, op, _, right) => (op.symbol().to_string(), vec![left, right]),
+            };
+            out.push_str(&format!("{}{}\n", first_prefix, label));
+            for (index, child) in children.iter().enumerate().rev() {
+                todo.push(if index + 1 == children.len() {
+                    (child, format!("{}└── ", prefix), format!("{}    ", prefix))
+                } else {
+                    (child, format!("{}├── ", prefix), format!("{}│   ", prefix))
+                });
+            }
+        }
+        out
     }
 }
 
-/// For each opening parenthesis,
-fn all_matching_parentheses(tokens: &[Token]) -> VecDeque<ParenthesisPair> {
-    let mut out = VecDeque::new();
-    let mut stack = Vec::<usize>::new();
-    for (index, token) in tokens.iter().enumerate() {
-        match token {
-            Token::RightParen => {
-                stack.push(index);
-            }
-            Token::LeftParen => {
-                out.push_back(ParenthesisPair::new(stack.pop().unwrap(), index));
+impl Drop for Expr {
+    // Dropping the children one after the other, rather than recursively, for the same reason
+    // as post_order
+    fn drop(&mut self) {
+        let mut todo = vec![];
+        let take_children = |expr: &mut Self, todo: &mut Vec<Self>| match expr {
+            Self::Num(_) => {}
+            Self::Neg(_, x) => todo.push(mem::replace(&mut **x, Self::Num(0))),
+            Self::Binary(left, _, _, right) => {
+                todo.push(mem::replace(&mut **left, Self::Num(0)));
+                todo.push(mem::replace(&mut **right, Self::Num(0)));
             }
-            _ => (),
+        };
+        take_children(self, &mut todo);
+        while let Some(mut expr) = todo.pop() {
+            take_children(&mut expr, &mut todo);
         }
     }
-    out
 }
 
-/// A term is either
-/// * a scalar value, or
-/// * a region between parenthesis (included) that was already evaluated
-fn evaluate_term(
-    tokens: &[Token],
-    region: &Range<usize>,
-    cache: &HashMap<usize, (Range<usize>, u64)>,
-) -> Option<u64> {
-    match tokens[region.start] {
-        Token::Num(x) => {
-            return Some(x);
+// How deeply expressions may nest, in parentheses, negations and right operands. Parsing them
+// recurses, so deeper ones are reported as errors instead of overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+/// Precedence climbing parser: every call to `parse_expression` consumes operators that bind at
+/// least as tightly as `min_precedence` and leaves the looser ones to its caller.
+struct Parser<'a> {
+    tokens: &'a [(Token, Span)],
+    pos: usize,
+    table: &'a PrecedenceTable,
+    // Where to point when we run out of tokens
+    end_of_input: Span,
+    // How many parentheses, negations and right operands we're in
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&(Token, Span)> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<&(Token, Span)> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+    // Parse something nested one level deeper, because of the token at span
+    fn nested(
+        &mut self,
+        span: &Span,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, ExprError>,
+    ) -> Result<Expr, ExprError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ExprError::new(
+                format!("Expression nested more than {} levels deep", MAX_DEPTH),
+                span.clone(),
+            ));
         }
-        _ => {
-            if let Some((_region, value)) = cache.get(&(region.start + 1)) {
-                assert_eq!(tokens[region.start], Token::RightParen);
-                return Some(*value);
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+    /// A number, a parenthesised expression or a negated operand.
+    /// Unary minus binds tighter than any binary operator, so -2 ^ 2 is 4.
+    fn parse_atom(&mut self) -> Result<Expr, ExprError> {
+        match self.next().cloned() {
+            Some((Token::Num(x), _)) => Ok(Expr::Num(x)),
+            Some((Token::Op(BinOp::Sub), span)) => {
+                let operand = self.nested(&span, Self::parse_atom)?;
+                Ok(Expr::Neg(span, Box::new(operand)))
+            }
+            Some((Token::LeftParen, opening_span)) => {
+                let expr = self.nested(&opening_span, |parser| parser.parse_expression(0))?;
+                match self.next() {
+                    Some((Token::RightParen, _)) => Ok(expr),
+                    _ => Err(ExprError::new(
+                        "Unclosed parenthesis".to_string(),
+                        opening_span,
+                    )),
+                }
             }
+            Some((token, span)) => Err(ExprError::new(
+                format!("Expected a number or '(', got {:?}", token),
+                span,
+            )),
+            None => Err(ExprError::new(
+                "Expected a number or '(', got the end of the expression".to_string(),
+                self.end_of_input.clone(),
+            )),
         }
     }
-    None
-}
-
-/// Returns the position of the next token after this term
-fn term_end(
-    tokens: &[Token],
-    region: &Range<usize>,
-    cache: &HashMap<usize, (Range<usize>, u64)>,
-) -> usize {
-    if tokens[region.start] == Token::RightParen {
-        cache[&(region.start + 1)].0.end + 1
-    } else {
-        region.start + 1
-    }
-}
-
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum PrecedenceRule {
-    None,
-    PlusFirst,
-}
-
-/// Contract:
-/// The input str
-/// * is never empty
-/// * any section between parenthesis was already evaluated
-/// * any pair of parenthesis contained within it was previously evaluated
-fn evaluate_expression(
-    tokens: &[Token],
-    region: &Range<usize>,
-    cache: &HashMap<usize, (Range<usize>, u64)>,
-    prec: PrecedenceRule,
-) -> u64 {
-    if term_end(tokens, region, cache) >= region.end {
-        return evaluate_term(tokens, region, cache).unwrap();
-    }
-
-    let first_term_region = region.start..term_end(tokens, region, cache);
-    let mut first_term = evaluate_term(tokens, &first_term_region, cache).unwrap();
-    let mut op = tokens[first_term_region.end];
-    let mut next_term_start = first_term_region.end + 1;
-    while prec == PrecedenceRule::PlusFirst && op == Token::Plus {
-        let next_term_region =
-            next_term_start..term_end(tokens, &(next_term_start..region.end), cache);
-        if next_term_region.end < region.end {
-            op = tokens[next_term_region.end];
-            next_term_start = next_term_region.end + 1;
-            first_term += evaluate_term(tokens, &next_term_region, cache).unwrap();
-        } else {
-            break;
+    // Precedences are u8, but a left associative operator at 255 asks for one more than that
+    fn parse_expression(&mut self, min_precedence: u16) -> Result<Expr, ExprError> {
+        let mut left = self.parse_atom()?;
+        while let Some((Token::Op(op), span)) = self.peek().cloned() {
+            let (precedence, assoc) = self.table.get(op).ok_or_else(|| {
+                ExprError::new(format!("No precedence defined for {:?}", op), span.clone())
+            })?;
+            if u16::from(precedence) < min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let next_min_precedence = match assoc {
+                Assoc::Left => u16::from(precedence) + 1,
+                Assoc::Right => u16::from(precedence),
+            };
+            let right =
+                self.nested(&span, |parser| parser.parse_expression(next_min_precedence))?;
+            left = Expr::Binary(Box::new(left), op, span, Box::new(right));
         }
+        Ok(left)
     }
-    let remaining_region = next_term_start..region.end;
-    let remaining_term = evaluate_expression(tokens, &remaining_region, cache, prec);
+}
 
-    let res = match op {
-        Token::Plus => first_term + remaining_term,
-        Token::Times => first_term * remaining_term,
-        _ => panic!("Not an operator: {:?}", op),
+pub fn parse(s: &str, table: &PrecedenceTable) -> Result<Expr, ExprError> {
+    let tokens = tokenize(s)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        pos: 0,
+        table,
+        end_of_input: s.len()..s.len(),
+        depth: 0,
     };
-    res
-}
-
-fn parse_expression(tokens: &[Token], prec: PrecedenceRule) -> u64 {
-    let parens = all_matching_parentheses(tokens);
-    // TODO: perf: once it works, Vec<u64> where the index represents the key is probably more
-    //             efficient
-    let mut cache = HashMap::new();
-    for p in parens {
-        let offset = p.content_offset();
-        cache.insert(
-            offset,
-            (
-                p.content(),
-                evaluate_expression(tokens, &p.content(), &cache, prec),
-            ),
-        );
+    let expr = parser.parse_expression(0)?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some((Token::RightParen, span)) => Err(ExprError::new(
+            "Unmatched closing parenthesis".to_string(),
+            span.clone(),
+        )),
+        Some((token, span)) => Err(ExprError::new(
+            format!("Expected an operator, got {:?}", token),
+            span.clone(),
+        )),
     }
-    evaluate_expression(tokens, &(0..tokens.len()), &cache, prec)
 }
 
-#[aoc(day18, part1)]
-fn part1(s: &str) -> u64 {
+pub fn evaluate(s: &str, table: &PrecedenceTable) -> Result<i64, ExprError> {
+    parse(s, table)?.evaluate()
+}
+
+fn sum_of_lines(s: &str, table: &PrecedenceTable) -> i64 {
     s.split_terminator('\n')
-        .map(|line| {
-            let mut tokens = tokenize(line);
-            tokens.reverse();
-            parse_expression(&tokens, PrecedenceRule::None)
-        })
+        .map(|line| evaluate(line, table).unwrap_or_else(|e| panic!("{}", e.render(line))))
         .sum()
 }
 
+#[aoc(day18, part1)]
+fn part1(s: &str) -> i64 {
+    sum_of_lines(s, &PrecedenceTable::flat())
+}
+
 #[aoc(day18, part2)]
-fn part2(s: &str) -> u64 {
-    s.split_terminator('\n')
-        .map(|line| {
-            let mut tokens = tokenize(line);
-            tokens.reverse();
-            parse_expression(&tokens, PrecedenceRule::PlusFirst)
-        })
-        .sum()
+fn part2(s: &str) -> i64 {
+    sum_of_lines(s, &PrecedenceTable::plus_first())
 }
 
 #[cfg(test)]
@@ -210,6 +493,129 @@ mod tests {
         assert_eq!(1445, part2(input));
     }
     #[test]
+    fn test_times_precedence() {
+        let table = PrecedenceTable::times_first();
+        assert_eq!(Ok(26), evaluate("2 * 3 + (4 * 5)", &table));
+        assert_eq!(
+            parse("1 + 2 * 3", &table),
+            Ok(Expr::Binary(
+                Box::new(Expr::Num(1)),
+                BinOp::Add,
+                2..3,
+                Box::new(Expr::Binary(
+                    Box::new(Expr::Num(2)),
+                    BinOp::Mul,
+                    6..7,
+                    Box::new(Expr::Num(3))
+                ))
+            ))
+        );
+    }
+    #[test]
+    fn test_more_operators() {
+        let table = PrecedenceTable::times_first();
+        let eval = |s| evaluate(s, &table);
+        assert_eq!(Ok(-38), eval("12-50"));
+        assert_eq!(Ok(2), eval("\t100 / 7 % 3\n"));
+        assert_eq!(Ok(512), eval("2 ^ 3 ^ 2"));
+        assert_eq!(Ok(4), eval("-2 ^ 2"));
+        assert_eq!(Ok(-7), eval("- (3 + 4)"));
+        assert_eq!(Ok(8), eval("2 * --4"));
+        assert!(eval("1 / (2 - 2)").is_err());
+        assert!(eval("7 % 0").is_err());
+        assert!(eval("2 ^ 64").is_err());
+        assert!(eval("2 ^ -1").is_err());
+        assert!(eval("-9223372036854775807 - 2").is_err());
+        // The highest precedence still groups to the left
+        let table = PrecedenceTable::new(&[(BinOp::Sub, u8::MAX, Assoc::Left)]);
+        assert_eq!(
+            parse("8 - 4 - 2", &table).map(|expr| expr.to_parenthesised()),
+            Ok("((8 - 4) - 2)".to_string())
+        );
+    }
+    #[test]
+    fn test_errors() {
+        let table = PrecedenceTable::plus_first();
+        let render = |s| evaluate(s, &table).unwrap_err().render(s);
+        assert_eq!(
+            render("1 / (2 - 2)"),
+            "Division by zero in 1 / 0\n1 / (2 - 2)\n  ^"
+        );
+        assert_eq!(
+            render("(1 + (2 * 3)"),
+            "Unclosed parenthesis\n(1 + (2 * 3)\n^"
+        );
+        assert_eq!(
+            render("1 + 2) * 3"),
+            "Unmatched closing parenthesis\n1 + 2) * 3\n     ^"
+        );
+        assert_eq!(
+            render("1 + 2 *"),
+            "Expected a number or '(', got the end of the expression\n1 + 2 *\n       ^"
+        );
+        assert_eq!(render("12 x 4"), "Unexpected character: x\n12 x 4\n   ^");
+        assert_eq!(
+            render("3 99999999999999999999"),
+            "Number too large: 99999999999999999999\n3 99999999999999999999\n  ^^^^^^^^^^^^^^^^^^^^"
+        );
+        assert_eq!(
+            evaluate(
+                "1 + 2 * 3",
+                &PrecedenceTable::new(&[(BinOp::Add, 1, Assoc::Left)])
+            ),
+            Err(ExprError::new(
+                "No precedence defined for Mul".to_string(),
+                6..7
+            ))
+        );
+        let deep = "-".repeat(100_000) + "1";
+        assert_eq!(
+            evaluate(&deep, &PrecedenceTable::flat()),
+            Err(ExprError::new(
+                "Expression nested more than 256 levels deep".to_string(),
+                256..257
+            ))
+        );
+        let deep = "(".repeat(100_000) + "1" + &")".repeat(100_000);
+        assert_eq!(evaluate(&deep, &table).unwrap_err().span, 256..257);
+        // Long chains of left associative operations aren't nested
+        let long = vec!["1"; 100_000].join(" + ");
+        assert_eq!(evaluate(&long, &PrecedenceTable::flat()), Ok(100_000));
+        assert_eq!(evaluate(&long, &table), Ok(100_000));
+        let expr = parse(&long, &table).unwrap();
+        assert_eq!(expr.to_rpn().len(), long.len());
+        assert_eq!(expr.to_parenthesised().len(), long.len() + 2 * 99_999);
+        let tree = parse(&vec!["1"; 1000].join(" + "), &table)
+            .unwrap()
+            .to_tree();
+        assert_eq!(tree.lines().count(), 1999);
+        let long = vec!["2"; 100_000].join(" ^ ");
+        assert_eq!(evaluate(&long, &table).unwrap_err().span, 1026..1027);
+        let shallow = "-(".repeat(127) + "1" + &")".repeat(127);
+        assert_eq!(evaluate(&shallow, &table), Ok(-1));
+    }
+    #[test]
+    fn test_conversions() {
+        let input = "2 * 3 + (4 * 5)";
+        let flat = parse(input, &PrecedenceTable::flat()).unwrap();
+        let plus_first = parse(input, &PrecedenceTable::plus_first()).unwrap();
+        assert_eq!(flat.to_parenthesised(), "((2 * 3) + (4 * 5))");
+        assert_eq!(plus_first.to_parenthesised(), "(2 * (3 + (4 * 5)))");
+        assert_eq!(flat.to_rpn(), "2 3 * 4 5 * +");
+        assert_eq!(plus_first.to_rpn(), "2 3 4 5 * + *");
+        assert_eq!(
+            plus_first.to_tree(),
+            "*\n├── 2\n└── +\n    ├── 3\n    └── *\n        ├── 4\n        └── 5\n"
+        );
+        let negated = parse("-(1 - -2)", &PrecedenceTable::flat()).unwrap();
+        assert_eq!(negated.to_parenthesised(), "(-(1 - (-2)))");
+        assert_eq!(negated.to_rpn(), "1 2 neg - neg");
+        assert_eq!(
+            negated.to_tree(),
+            "-\n└── -\n    ├── 1\n    └── -\n        └── 2\n"
+        );
+    }
+    #[test]
     fn test_part1() {
         assert_eq!(6923486965641, part1(&input()))
     }
@@ -1,13 +1,28 @@
+use crate::rng::Rng;
 use aoc_runner_derive::{aoc, aoc_generator};
 use itertools::Itertools;
-use std::iter::repeat;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::collections::BTreeSet;
+use std::iter::once;
+use std::ops::Range;
+
+// Past this depth, or this many rule expansions, samples take the shortest way out of recursive
+// rules. The depth alone isn't enough: a rule like "0: 0 0 0 | 1" branches out exponentially well
+// before getting deep.
+const MAX_SAMPLE_DEPTH: usize = 50;
+const MAX_SAMPLE_EXPANSIONS: usize = 1000;
+
+// Rules used from several places are copied into the NFA each time, so rules which aren't
+// recursive can still compile to automata exponentially big. Past this many states, we give up
+// on compiling them.
+const MAX_COMPILED_STATES: usize = 10_000;
 
 #[derive(Clone, Debug)]
 enum Rule {
-    A,
-    B,
-    Seq(Vec<usize>),
-    EitherOr(Vec<usize>, Vec<usize>),
+    // Matches this exact text, which may be any number of characters long
+    Terminal(String),
+    // Any one of these sequences of rules
+    Alternatives(Vec<Vec<usize>>),
 }
 
 fn parse_ints(s: &str) -> Vec<usize> {
@@ -16,87 +31,738 @@ fn parse_ints(s: &str) -> Vec<usize> {
 
 impl From<&str> for Rule {
     fn from(s: &str) -> Self {
-        if s == "\"a\"" {
-            Self::A
-        } else if s == "\"b\"" {
-            Self::B
-        } else if s.contains("|") {
-            let (either, or) = s.split(" | ").collect_tuple().unwrap();
-            Self::EitherOr(parse_ints(either), parse_ints(or))
+        if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+            Self::Terminal(s[1..s.len() - 1].to_string())
         } else {
-            Self::Seq(parse_ints(s))
+            Self::Alternatives(s.split(" | ").map(parse_ints).collect())
         }
     }
 }
 
 #[derive(Clone, Debug)]
-struct Rules {
+pub struct Rules {
     // Sorted. The index in the Vec is the key to access the Rule
     rules: Vec<Rule>,
-    // Feels wasteful to store a full byte of information with a char for something that has only
-    // two states. An enum would probably be more expressive, but I'm feeling lazy. Let's just
-    // agree true is "a" and false is "b" and not tell anyone. This will be our little secret ;)
-    messages: Vec<Vec<bool>>,
+    messages: Vec<String>,
 }
 
 impl From<&str> for Rules {
     fn from(s: &str) -> Self {
         let (rules_section, messages_section) = s.split("\n\n").collect_tuple().unwrap();
-        let len = rules_section.split_terminator("\n").count();
-        let mut rules = repeat(Rule::A).take(len).collect::<Vec<_>>();
-        rules_section.split_terminator('\n').for_each(|line| {
-            let (index, rule) = line.split(": ").collect_tuple().unwrap();
-            rules[index.parse::<usize>().unwrap()] = Rule::from(rule);
-        });
-        let messages = messages_section
-            .split_terminator("\n")
+        let indexed_rules = rules_section
+            .split_terminator('\n')
             .map(|line| {
-                line.chars()
-                    .map(|c| {
-                        if c == 'a' {
-                            true
-                        } else if c == 'b' {
-                            false
-                        } else {
-                            panic!("Expected only a or b chars in input for messages");
-                        }
-                    })
-                    .collect()
+                // Terminals may contain ": " too
+                let (index, rule) = line.split_once(": ").unwrap();
+                (index.parse::<usize>().unwrap(), Rule::from(rule))
             })
+            .collect::<Vec<_>>();
+        // Rule numbers may have gaps: those match nothing
+        let len = indexed_rules
+            .iter()
+            .map(|(index, _)| index + 1)
+            .max()
+            .unwrap_or(0);
+        let mut rules = vec![Rule::Alternatives(vec![]); len];
+        for (index, rule) in indexed_rules {
+            rules[index] = rule;
+        }
+        let messages = messages_section
+            .split_terminator('\n')
+            .map(|line| line.to_string())
             .collect();
         Rules { rules, messages }
     }
 }
 
+/// Which rule matched which part of a message (a byte range), and how
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseTree {
+    pub rule: usize,
+    pub span: Range<usize>,
+    pub children: Vec<ParseTree>,
+}
+
+impl ParseTree {
+    /// One line per rule, children indented under their parent:
+    /// ```text
+    /// 0 [0..3] "aab"
+    ///   1 [0..2] "aa"
+    ///     3 [0..1] "a"
+    ///     3 [1..2] "a"
+    ///   2 [2..3] "b"
+    /// ```
+    pub fn render(&self, message: &str) -> String {
+        let mut out = String::new();
+        self.write(message, 0, &mut out);
+        out
+    }
+    fn write(&self, message: &str, depth: usize, out: &mut String) {
+        out.push_str(&format!(
+            "{}{} [{}..{}] {:?}\n",
+            "  ".repeat(depth),
+            self.rule,
+            self.span.start,
+            self.span.end,
+            &message[self.span.clone()]
+        ));
+        for child in &self.children {
+            child.write(message, depth + 1, out);
+        }
+    }
+}
+
+/// The completed items of an Earley chart, as (rule, alternative, start, end)
+type Completed = FxHashSet<(usize, usize, usize, usize)>;
+
+/// A partially matched alternative of a rule: we are trying to match `rules[rule]`'s `alternative`
+/// from position `origin` in the message and have matched everything before `dot` so far.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct EarleyItem {
+    rule: usize,
+    alternative: usize,
+    dot: usize,
+    origin: usize,
+}
+
+impl EarleyItem {
+    fn advance(&self) -> Self {
+        Self {
+            dot: self.dot + 1,
+            ..*self
+        }
+    }
+}
+
 impl Rules {
-    // If matches the rule,
-    // return Some(unmatched part of the message),
-    // else None
-    fn matches_rule<'a>(&self, message: &'a [bool], rule: &Rule) -> Option<&'a [bool]> {
-        match rule {
-            Rule::A => message
-                .get(0)
-                .and_then(|a| if *a { Some(&message[1..]) } else { None }),
-            Rule::B => message
-                .get(0)
-                .and_then(|a| if !*a { Some(&message[1..]) } else { None }),
-            Rule::Seq(rules) => {
-                let mut part = message;
-                for index in rules {
-                    let next_part = self.matches_rule(part, &self.rules[*index]);
-                    if let Some(next_part) = next_part {
-                        part = next_part;
+    fn alternative(&self, item: &EarleyItem) -> &[usize] {
+        match &self.rules[item.rule] {
+            Rule::Alternatives(alternatives) => &alternatives[item.alternative],
+            _ => panic!("Terminal rules don't have alternatives"),
+        }
+    }
+    fn next_rule(&self, item: &EarleyItem) -> Option<usize> {
+        self.alternative(item).get(item.dot).copied()
+    }
+    /// Where the message continues after this terminal, if it appears at that position
+    fn scan(terminal: &str, message: &str, position: usize) -> Option<usize> {
+        if message[position..].starts_with(terminal) {
+            Some(position + terminal.len())
+        } else {
+            None
+        }
+    }
+    /// Earley chart for matching the message against a rule that has alternatives.
+    /// chart[i] holds the items that have matched message[origin..i], i being a byte offset in
+    /// the message.
+    fn earley_chart(&self, message: &str, rule: usize) -> Vec<Vec<EarleyItem>> {
+        let n_alternatives = match &self.rules[rule] {
+            Rule::Alternatives(alternatives) => alternatives.len(),
+            Rule::Terminal(_) => panic!("Terminal rules don't need a chart"),
+        };
+        let mut chart: Vec<Vec<EarleyItem>> = vec![Vec::new(); message.len() + 1];
+        let mut seen: Vec<FxHashSet<EarleyItem>> = vec![FxHashSet::default(); message.len() + 1];
+        // Rules that matched an empty string at each position (possible with "" terminals)
+        let mut nullable: Vec<FxHashSet<usize>> = vec![FxHashSet::default(); message.len() + 1];
+        let mut add = |chart: &mut Vec<Vec<EarleyItem>>, position: usize, item| {
+            if seen[position].insert(item) {
+                chart[position].push(item);
+            }
+        };
+        for alternative in 0..n_alternatives {
+            let item = EarleyItem {
+                rule,
+                alternative,
+                dot: 0,
+                origin: 0,
+            };
+            add(&mut chart, 0, item);
+        }
+        for position in 0..=message.len() {
+            // The set grows as we process it
+            let mut index = 0;
+            while index < chart[position].len() {
+                let item = chart[position][index];
+                index += 1;
+                match self.next_rule(&item) {
+                    // Complete: advance everything that was waiting on this rule
+                    None => {
+                        if item.origin == position {
+                            nullable[position].insert(item.rule);
+                        }
+                        for waiting in chart[item.origin].clone() {
+                            if self.next_rule(&waiting) == Some(item.rule) {
+                                add(&mut chart, position, waiting.advance());
+                            }
+                        }
+                    }
+                    Some(next) => match &self.rules[next] {
+                        // Predict: start matching each alternative of the next rule here
+                        Rule::Alternatives(alternatives) => {
+                            for alternative in 0..alternatives.len() {
+                                let predicted = EarleyItem {
+                                    rule: next,
+                                    alternative,
+                                    dot: 0,
+                                    origin: position,
+                                };
+                                add(&mut chart, position, predicted);
+                            }
+                            // It already completed here without consuming anything, so
+                            // it won't complete again to advance this item
+                            if nullable[position].contains(&next) {
+                                add(&mut chart, position, item.advance());
+                            }
+                        }
+                        // Scan: consume the terminal from the message
+                        Rule::Terminal(terminal) => {
+                            if let Some(end) = Self::scan(terminal, message, position) {
+                                add(&mut chart, end, item.advance());
+                            }
+                        }
+                    },
+                }
+            }
+        }
+        chart
+    }
+    /// Earley recognizer: does the whole message match the rule?
+    /// Unlike a greedy descent, this explores every alternative, so it copes with any rule set,
+    /// recursive or not.
+    pub fn matches(&self, message: &str, rule: usize) -> bool {
+        match &self.rules[rule] {
+            Rule::Terminal(terminal) => message == terminal,
+            Rule::Alternatives(_) => {
+                self.earley_chart(message, rule)[message.len()]
+                    .iter()
+                    .any(|item| {
+                        item.rule == rule && item.origin == 0 && self.next_rule(item).is_none()
+                    })
+            }
+        }
+    }
+    /// How the whole message matches the rule, if it does. Ambiguous grammars may have several
+    /// parse trees for the same message: this returns the first one found, preferring earlier
+    /// alternatives.
+    pub fn parse(&self, message: &str, rule: usize) -> Option<ParseTree> {
+        let completed = match &self.rules[rule] {
+            Rule::Terminal(_) => Completed::default(),
+            Rule::Alternatives(_) => self
+                .earley_chart(message, rule)
+                .iter()
+                .enumerate()
+                .flat_map(|(end, items)| {
+                    items
+                        .iter()
+                        .filter(|item| self.next_rule(item).is_none())
+                        .map(move |item| (item.rule, item.alternative, item.origin, end))
+                })
+                .collect(),
+        };
+        self.build_tree(message, rule, 0..message.len(), &completed, &mut vec![])
+    }
+    /// Reconstruct how `rule` matched `message[span]` from the completed items.
+    /// `path` holds the (rule, span) pairs being built above us, so that rules matching an empty
+    /// string can't send us round in circles.
+    fn build_tree(
+        &self,
+        message: &str,
+        rule: usize,
+        span: Range<usize>,
+        completed: &Completed,
+        path: &mut Vec<(usize, Range<usize>)>,
+    ) -> Option<ParseTree> {
+        match &self.rules[rule] {
+            Rule::Terminal(terminal) => {
+                if &message[span.clone()] == terminal {
+                    Some(ParseTree {
+                        rule,
+                        span,
+                        children: vec![],
+                    })
+                } else {
+                    None
+                }
+            }
+            Rule::Alternatives(alternatives) => {
+                if path.contains(&(rule, span.clone())) {
+                    return None;
+                }
+                path.push((rule, span.clone()));
+                let children = (0..alternatives.len())
+                    .filter(|alternative| {
+                        completed.contains(&(rule, *alternative, span.start, span.end))
+                    })
+                    .find_map(|alternative| {
+                        self.build_children(
+                            message,
+                            &alternatives[alternative],
+                            span.clone(),
+                            completed,
+                            path,
+                        )
+                    });
+                path.pop();
+                children.map(|children| ParseTree {
+                    rule,
+                    span,
+                    children,
+                })
+            }
+        }
+    }
+    /// Split the span between the rules of the sequence, on character boundaries only
+    fn build_children(
+        &self,
+        message: &str,
+        sequence: &[usize],
+        span: Range<usize>,
+        completed: &Completed,
+        path: &mut Vec<(usize, Range<usize>)>,
+    ) -> Option<Vec<ParseTree>> {
+        let (first, rest) = match sequence.split_first() {
+            Some(split) => split,
+            None => return if span.is_empty() { Some(vec![]) } else { None },
+        };
+        (span.start..=span.end)
+            .filter(|middle| message.is_char_boundary(*middle))
+            .find_map(|middle| {
+                let first_tree =
+                    self.build_tree(message, *first, span.start..middle, completed, path)?;
+                let mut children =
+                    self.build_children(message, rest, middle..span.end, completed, path)?;
+                children.insert(0, first_tree);
+                Some(children)
+            })
+    }
+    /// How many messages the rule matches: with a DFA when the rule compiles to one, or else
+    /// with the Earley recognizer
+    pub fn count_matching(&self, rule: usize) -> usize {
+        match self.compile(rule) {
+            Some(dfa) => self.messages.iter().filter(|msg| dfa.matches(msg)).count(),
+            None => self
+                .messages
+                .iter()
+                .filter(|msg| self.matches(msg, rule))
+                .count(),
+        }
+    }
+    /// Can this rule refer back to itself, directly or not?
+    /// Tracks the rules on the current path to spot cycles, and the rules already known not to
+    /// lead to any, so that rules used from several places are only explored once.
+    fn is_recursive(&self, rule: usize) -> bool {
+        fn visit(rules: &Rules, rule: usize, path: &mut Vec<bool>, done: &mut Vec<bool>) -> bool {
+            if path[rule] {
+                return true;
+            }
+            if done[rule] {
+                return false;
+            }
+            path[rule] = true;
+            let recursive = match &rules.rules[rule] {
+                Rule::Terminal(_) => false,
+                Rule::Alternatives(alternatives) => alternatives
+                    .iter()
+                    .flatten()
+                    .any(|next| visit(rules, *next, path, done)),
+            };
+            path[rule] = false;
+            done[rule] = true;
+            recursive
+        }
+        let len = self.rules.len();
+        visit(self, rule, &mut vec![false; len], &mut vec![false; len])
+    }
+    /// The language of a non recursive rule as a regular expression,
+    /// e.g. a((aa|bb)(ab|ba)|(ab|ba)(aa|bb))b
+    /// Recursive rules can describe non regular languages, so they get None.
+    pub fn to_regex(&self, rule: usize) -> Option<String> {
+        if self.is_recursive(rule) {
+            None
+        } else {
+            Some(self.regex(rule))
+        }
+    }
+    fn regex(&self, rule: usize) -> String {
+        match &self.rules[rule] {
+            Rule::Terminal(terminal) => terminal
+                .chars()
+                .map(|c| {
+                    if "\\.+*?()|[]{}^$".contains(c) {
+                        format!("\\{}", c)
                     } else {
-                        return None;
+                        c.to_string()
                     }
+                })
+                .collect(),
+            // Matches nothing at all
+            Rule::Alternatives(alternatives) if alternatives.is_empty() => "[^\\s\\S]".to_string(),
+            Rule::Alternatives(alternatives) => {
+                let alternatives = alternatives
+                    .iter()
+                    .map(|sequence| {
+                        sequence
+                            .iter()
+                            .map(|next| self.regex(*next))
+                            .collect::<String>()
+                    })
+                    .collect::<Vec<_>>();
+                if alternatives.len() == 1 {
+                    alternatives[0].clone()
+                } else {
+                    format!("({})", alternatives.join("|"))
                 }
-                Some(part)
             }
-            Rule::EitherOr(either, or) => self
-                .matches_rule(message, &Rule::Seq(either.clone()))
-                .or_else(|| self.matches_rule(message, &Rule::Seq(or.clone()))),
         }
     }
+    /// Adds the states recognizing this rule to the NFA, starting from `from`.
+    /// Returns the state reached after a match, or None if the NFA grew too big.
+    fn build_nfa(&self, rule: usize, nfa: &mut Nfa, from: usize) -> Option<usize> {
+        match &self.rules[rule] {
+            Rule::Terminal(terminal) => terminal.chars().try_fold(from, |state, c| {
+                let next = nfa.add_state()?;
+                nfa.add_edge(state, Some(c), next);
+                Some(next)
+            }),
+            Rule::Alternatives(alternatives) => {
+                let end = nfa.add_state()?;
+                for sequence in alternatives {
+                    let state = sequence
+                        .iter()
+                        .try_fold(from, |state, next| self.build_nfa(*next, nfa, state))?;
+                    nfa.add_edge(state, None, end);
+                }
+                Some(end)
+            }
+        }
+    }
+    /// A minimal DFA recognizing this rule, or None if the rule is recursive and can't be
+    /// compiled, or if its automata would take more than MAX_COMPILED_STATES states
+    pub fn compile(&self, rule: usize) -> Option<Dfa> {
+        if self.is_recursive(rule) {
+            return None;
+        }
+        let mut nfa = Nfa::default();
+        let start = nfa.add_state()?;
+        let accepting = self.build_nfa(rule, &mut nfa, start)?;
+        Some(Dfa::from_nfa(&nfa, start, accepting)?.minimized())
+    }
+    /// Every message matched by a non recursive rule, or None for recursive rules, whose language
+    /// may well be infinite
+    pub fn language(&self, rule: usize) -> Option<BTreeSet<String>> {
+        if self.is_recursive(rule) {
+            None
+        } else {
+            Some(self.generate(rule, &mut FxHashMap::default()))
+        }
+    }
+    fn generate(
+        &self,
+        rule: usize,
+        cache: &mut FxHashMap<usize, BTreeSet<String>>,
+    ) -> BTreeSet<String> {
+        if let Some(language) = cache.get(&rule) {
+            return language.clone();
+        }
+        let language: BTreeSet<String> = match &self.rules[rule] {
+            Rule::Terminal(terminal) => once(terminal.clone()).collect(),
+            Rule::Alternatives(alternatives) => alternatives
+                .iter()
+                .flat_map(|sequence| {
+                    sequence.iter().fold(
+                        once(String::new()).collect(),
+                        |prefixes: BTreeSet<String>, next| {
+                            let suffixes = self.generate(*next, cache);
+                            prefixes
+                                .iter()
+                                .cartesian_product(suffixes.iter())
+                                .map(|(prefix, suffix)| format!("{}{}", prefix, suffix))
+                                .collect()
+                        },
+                    )
+                })
+                .collect(),
+        };
+        cache.insert(rule, language.clone());
+        language
+    }
+    /// How many distinct messages a non recursive rule matches.
+    /// Counts paths through the DFA rather than enumerating them, so it stays cheap for huge
+    /// languages, and ambiguous rules don't count the same message twice.
+    pub fn count_language(&self, rule: usize) -> Option<u128> {
+        fn paths(dfa: &Dfa, state: usize, cache: &mut FxHashMap<usize, u128>) -> u128 {
+            if let Some(count) = cache.get(&state) {
+                return *count;
+            }
+            // The language is finite, so the DFA has no cycles
+            let count = dfa.accepting[state] as u128
+                + dfa.transitions[state]
+                    .values()
+                    .map(|next| paths(dfa, *next, cache))
+                    .sum::<u128>();
+            cache.insert(state, count);
+            count
+        }
+        self.compile(rule)
+            .map(|dfa| paths(&dfa, 0, &mut FxHashMap::default()))
+    }
+    /// For each rule, the height of its shallowest derivation tree, or None if it can't match
+    /// anything
+    fn heights(&self) -> Vec<Option<usize>> {
+        let mut heights = vec![None; self.rules.len()];
+        loop {
+            let next_heights = self
+                .rules
+                .iter()
+                .map(|rule| match rule {
+                    Rule::Terminal(_) => Some(0),
+                    Rule::Alternatives(alternatives) => alternatives
+                        .iter()
+                        .filter_map(|sequence| {
+                            sequence_height(sequence, &heights).map(|height| height + 1)
+                        })
+                        .min(),
+                })
+                .collect::<Vec<_>>();
+            if next_heights == heights {
+                return heights;
+            }
+            heights = next_heights;
+        }
+    }
+    fn derive(
+        &self,
+        rule: usize,
+        heights: &[Option<usize>],
+        depth: usize,
+        expansions: &mut usize,
+        rng: &mut Rng,
+        out: &mut String,
+    ) {
+        match &self.rules[rule] {
+            Rule::Terminal(terminal) => out.push_str(terminal),
+            Rule::Alternatives(alternatives) => {
+                let height = |sequence: &Vec<usize>| sequence_height(sequence, heights);
+                let candidates = alternatives
+                    .iter()
+                    .filter(|sequence| height(sequence).is_some())
+                    .collect::<Vec<_>>();
+                *expansions += 1;
+                let sequence = if depth < MAX_SAMPLE_DEPTH && *expansions < MAX_SAMPLE_EXPANSIONS {
+                    candidates[rng.below(candidates.len())]
+                } else {
+                    candidates
+                        .into_iter()
+                        .min_by_key(|sequence| height(sequence))
+                        .unwrap()
+                };
+                for next in sequence {
+                    self.derive(*next, heights, depth + 1, expansions, rng, out);
+                }
+            }
+        }
+    }
+    /// A random message matching this rule, recursive or not. None if the rule matches nothing.
+    pub fn sample(&self, rule: usize, rng: &mut Rng) -> Option<String> {
+        let heights = self.heights();
+        heights[rule]?;
+        let mut out = String::new();
+        self.derive(rule, &heights, 0, &mut 0, rng, &mut out);
+        Some(out)
+    }
+    /// A random message that doesn't match this rule, one character away from one that does.
+    /// None if we didn't stumble upon one after a fair number of attempts.
+    pub fn sample_near_miss(&self, rule: usize, rng: &mut Rng) -> Option<String> {
+        let alphabet = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Terminal(terminal) => Some(terminal.chars()),
+                _ => None,
+            })
+            .flatten()
+            .collect::<BTreeSet<char>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        (0..100).find_map(|_| {
+            let mut chars = self.sample(rule, rng)?.chars().collect::<Vec<_>>();
+            let position = rng.below(chars.len() + 1);
+            // Insert, delete or substitute one character
+            match rng.below(3) {
+                0 if !alphabet.is_empty() => {
+                    chars.insert(position, alphabet[rng.below(alphabet.len())])
+                }
+                1 if position < chars.len() => {
+                    chars.remove(position);
+                }
+                2 if position < chars.len() && !alphabet.is_empty() => {
+                    chars[position] = alphabet[rng.below(alphabet.len())]
+                }
+                _ => return None,
+            }
+            let message = chars.into_iter().collect::<String>();
+            if self.matches(&message, rule) {
+                None
+            } else {
+                Some(message)
+            }
+        })
+    }
+    /// A copy of these rules, with one of them replaced
+    pub fn with_rule(&self, index: usize, rule: &str) -> Self {
+        let mut rules = self.clone();
+        if index >= rules.rules.len() {
+            rules.rules.resize(index + 1, Rule::Alternatives(vec![]));
+        }
+        rules.rules[index] = rule.into();
+        rules
+    }
+}
+
+/// Height of the tallest of these rules, or None if any of them can't match anything
+fn sequence_height(sequence: &[usize], heights: &[Option<usize>]) -> Option<usize> {
+    sequence
+        .iter()
+        .map(|next| heights[*next])
+        .try_fold(0, |acc, height| height.map(|height| acc.max(height)))
+}
+
+/// Thompson NFA: edges labelled None are epsilon moves
+#[derive(Debug, Default)]
+struct Nfa {
+    edges: Vec<Vec<(Option<char>, usize)>>,
+}
+
+impl Nfa {
+    // None once there are MAX_COMPILED_STATES states already
+    fn add_state(&mut self) -> Option<usize> {
+        if self.edges.len() == MAX_COMPILED_STATES {
+            return None;
+        }
+        self.edges.push(vec![]);
+        Some(self.edges.len() - 1)
+    }
+    fn add_edge(&mut self, from: usize, label: Option<char>, to: usize) {
+        self.edges[from].push((label, to));
+    }
+    fn epsilon_closure(&self, states: BTreeSet<usize>) -> BTreeSet<usize> {
+        let mut closure = states.clone();
+        let mut stack = states.into_iter().collect::<Vec<_>>();
+        while let Some(state) = stack.pop() {
+            for (label, next) in &self.edges[state] {
+                if label.is_none() && closure.insert(*next) {
+                    stack.push(*next);
+                }
+            }
+        }
+        closure
+    }
+}
+
+/// State 0 is the start. A missing transition means the message is rejected.
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    transitions: Vec<FxHashMap<char, usize>>,
+    accepting: Vec<bool>,
+}
+
+impl Dfa {
+    /// Subset construction: each DFA state is the set of NFA states we could be in.
+    /// None if that takes more than MAX_COMPILED_STATES states.
+    fn from_nfa(nfa: &Nfa, start: usize, accepting: usize) -> Option<Self> {
+        let start = nfa.epsilon_closure(once(start).collect());
+        let mut index = FxHashMap::default();
+        index.insert(start.clone(), 0);
+        let mut subsets = vec![start];
+        let mut dfa = Self {
+            transitions: vec![],
+            accepting: vec![],
+        };
+        while dfa.transitions.len() < subsets.len() {
+            let subset = subsets[dfa.transitions.len()].clone();
+            let mut moves = FxHashMap::<char, BTreeSet<usize>>::default();
+            for state in &subset {
+                for (label, next) in &nfa.edges[*state] {
+                    if let Some(c) = label {
+                        moves.entry(*c).or_default().insert(*next);
+                    }
+                }
+            }
+            let mut transitions = FxHashMap::default();
+            for (c, targets) in moves {
+                let target = nfa.epsilon_closure(targets);
+                let next = *index.entry(target.clone()).or_insert_with(|| {
+                    subsets.push(target);
+                    subsets.len() - 1
+                });
+                transitions.insert(c, next);
+            }
+            if subsets.len() > MAX_COMPILED_STATES {
+                return None;
+            }
+            dfa.transitions.push(transitions);
+            dfa.accepting.push(subset.contains(&accepting));
+        }
+        Some(dfa)
+    }
+    /// Moore's algorithm: split states into classes until states of a class can't be told apart
+    /// by where their transitions lead.
+    fn minimized(&self) -> Self {
+        let alphabet = self
+            .transitions
+            .iter()
+            .flat_map(|transitions| transitions.keys().copied())
+            .collect::<BTreeSet<char>>();
+        let mut classes = self
+            .accepting
+            .iter()
+            .map(|accepting| *accepting as usize)
+            .collect::<Vec<_>>();
+        loop {
+            // None stands for the implicit dead state
+            let signatures = (0..self.transitions.len())
+                .map(|state| {
+                    let targets = alphabet
+                        .iter()
+                        .map(|c| self.transitions[state].get(c).map(|next| classes[*next]))
+                        .collect::<Vec<_>>();
+                    (classes[state], targets)
+                })
+                .collect::<Vec<_>>();
+            // Number the classes in order of first appearance, so the start state stays 0
+            let mut numbering = FxHashMap::default();
+            let next_classes = signatures
+                .into_iter()
+                .map(|signature| {
+                    let len = numbering.len();
+                    *numbering.entry(signature).or_insert(len)
+                })
+                .collect::<Vec<_>>();
+            let n_classes = numbering.len();
+            let done = n_classes == classes.iter().collect::<FxHashSet<_>>().len();
+            classes = next_classes;
+            if done {
+                let mut minimized = Self {
+                    transitions: vec![FxHashMap::default(); n_classes],
+                    accepting: vec![false; n_classes],
+                };
+                for (state, class) in classes.iter().enumerate() {
+                    minimized.accepting[*class] = self.accepting[state];
+                    minimized.transitions[*class] = self.transitions[state]
+                        .iter()
+                        .map(|(c, next)| (*c, classes[*next]))
+                        .collect();
+                }
+                return minimized;
+            }
+        }
+    }
+    pub fn matches(&self, message: &str) -> bool {
+        message
+            .chars()
+            .try_fold(0, |state, c| self.transitions[state].get(&c).copied())
+            .is_some_and(|state| self.accepting[state])
+    }
 }
 
 #[aoc_generator(day19)]
@@ -106,67 +772,15 @@ fn parse_input(s: &str) -> Rules {
 
 #[aoc(day19, part1)]
 fn part1(rules: &Rules) -> usize {
-    rules
-        .messages
-        .iter()
-        .filter(|msg| rules.matches_rule(msg, &rules.rules[0]) == Some(&[]))
-        .count()
+    rules.count_matching(0)
 }
 
 #[aoc(day19, part2)]
 fn part2(rules: &Rules) -> usize {
-    // Before:
-    // 0: 8 11
-    // 8: 42
-    // 11: 42 31
-    // Now:
-    // 0: 8 11
-    // 8: 42 | 42 8
-    // 11: 42 31 | 42 11 31
-    //
-    // Rule 8 now means: any number of instances of rule 42 greater than one
-    // Rule 11 now means: EitherOr([42 31], [42 11 31]), which is to say: either 42 31 or a
-    // certain number of 42s followed by the same number of 31s.
-    //
-    // So rule 0 becomes:
-    // N 42s and M 31s where N >= 2 and M < N
-    //
-    // From the original case,
-    // We can keep adding 42s in the front.
-    // If a message matches, we can count it and stop looking at it in the future
-    // If a message doesn't match the N 42s, it can be ruled out and not counted
-    // We will eventually have counted or ruled out all messages
-    let mut n_matching = 0;
-    let mut n_unknown = rules.messages.len();
-    let mut unknown_messages = repeat(true).take(rules.messages.len()).collect::<Vec<_>>();
-    for n_42s in 2..100 {
-        for n_31s in 1..n_42s {
-            let previously_unknown = unknown_messages.clone();
-            for (index, message) in rules
-                .messages
-                .iter()
-                .enumerate()
-                .filter(|(index, _)| previously_unknown[*index])
-            {
-                let first_rule = Rule::Seq(repeat(42).take(n_42s).collect());
-                if let Some(second_part) = rules.matches_rule(message, &first_rule) {
-                    let second_rule = Rule::Seq(repeat(31).take(n_31s).collect());
-                    if let Some(&[]) = rules.matches_rule(second_part, &second_rule) {
-                        n_matching += 1;
-                        n_unknown -= 1;
-                        unknown_messages[index] = false;
-                    }
-                } else {
-                    n_unknown -= 1;
-                    unknown_messages[index] = false;
-                }
-                if n_unknown == 0 {
-                    return n_matching;
-                }
-            }
-        }
-    }
-    panic!("This should be unreachable as we should have ran out of unknowns earlier");
+    rules
+        .with_rule(8, "42 | 42 8")
+        .with_rule(11, "42 31 | 42 11 31")
+        .count_matching(0)
 }
 
 #[cfg(test)]
@@ -180,6 +794,163 @@ mod tests {
         assert_eq!(part1(&input()), 118)
     }
     #[test]
+    fn test_recursive_rules() {
+        // Ambiguous and left recursive: greedily taking the first alternative would never match
+        let rules = parse_input(
+            "0: 1 2 | 0 0\n1: 3 | 1 1\n2: \"b\"\n3: \"a\"\n\nab\naab\naabab\nba\nabb\n",
+        );
+        assert_eq!(rules.count_matching(0), 3);
+        let rules = parse_input("0: 1 2\n1: \"key: \"\n2: \"value\"\n\nkey: value\nkey:value\n");
+        assert_eq!(rules.count_matching(0), 1);
+    }
+    #[test]
+    fn test_terminals() {
+        let rules = parse_input(
+            "0: 1 2 1\n1: 4 | 3\n2: 5 | 1 2\n3: \"\"\n4: \"😀\"\n5: \"-->\"\n\n😀-->😀\n-->\n😀😀-->\n😀->😀\n--->\n",
+        );
+        assert_eq!(rules.count_matching(0), 3);
+    }
+    #[test]
+    fn test_compile() {
+        let rules = parse_input(
+            "0: 4 1 5\n1: 2 3 | 3 2\n2: 4 4 | 5 5\n3: 4 5 | 5 4\n4: \"a\"\n5: \"b\"\n\nababbb\nbababa\nabbbab\naaabbb\naaaabbb\n",
+        );
+        assert_eq!(
+            rules.to_regex(0),
+            Some("a((aa|bb)(ab|ba)|(ab|ba)(aa|bb))b".to_string())
+        );
+        let dfa = rules.compile(0).unwrap();
+        // One state per length from 0 to 6, plus 2 to remember if the first pair was doubled
+        assert_eq!(dfa.accepting.len(), 10);
+        assert_eq!(rules.count_matching(0), 2);
+        let recursive = rules.with_rule(1, "2 3 | 1 1");
+        assert_eq!(recursive.to_regex(0), None);
+        assert!(recursive.compile(0).is_none());
+    }
+    #[test]
+    fn test_compile_too_big() {
+        // Each rule uses the next two twice over, so its NFA would double in size with each one
+        let depth = 20;
+        let mut rules = (0..depth)
+            .map(|k| format!("{}: {} {} | {} {}", k, k + 1, k + 2, k + 2, k + 1))
+            .collect::<Vec<_>>();
+        rules.push(format!("{}: \"a\"", depth));
+        rules.push(format!("{}: \"b\"", depth + 1));
+        // One message matched by rule 4, from the first alternative all the way down
+        let mut messages = vec!["a".to_string(), "b".to_string()];
+        for _ in 4..depth {
+            let next = format!("{}{}", messages[0], messages[1]);
+            messages = vec![next, messages.remove(0)];
+        }
+        let matching = messages.remove(0);
+        let rules = parse_input(&format!(
+            "{}\n\n{}\nab\n{}b\n",
+            rules.join("\n"),
+            matching,
+            matching
+        ));
+        assert!(!rules.is_recursive(0));
+        assert!(rules.compile(0).is_none());
+        assert_eq!(rules.count_matching(0), 0);
+        assert!(rules.compile(4).is_none());
+        assert_eq!(rules.count_matching(4), 1);
+        assert!(rules.compile(depth - 6).is_some());
+    }
+    #[test]
+    fn test_compiled_agrees_with_earley() {
+        let rules = input();
+        let dfa = rules.compile(0).unwrap();
+        for message in rules.messages.iter() {
+            assert_eq!(dfa.matches(message), rules.matches(message, 0));
+        }
+    }
+    #[test]
+    fn test_generate() {
+        let rules = parse_input(
+            "0: 4 1 5\n1: 2 3 | 3 2\n2: 4 4 | 5 5\n3: 4 5 | 5 4\n4: \"a\"\n5: \"b\"\n\n",
+        );
+        let language = rules.language(0).unwrap();
+        assert_eq!(language.len(), 8);
+        assert!(language.contains("aaaabb"));
+        assert!(language.contains("abbabb"));
+        assert_eq!(rules.count_language(0), Some(8));
+        // Ambiguous: "aa" can be derived in two ways, but only counts once
+        let ambiguous = rules.with_rule(6, "4 4 | 4 7").with_rule(7, "4");
+        assert_eq!(ambiguous.count_language(6), Some(1));
+        assert_eq!(ambiguous.language(6).unwrap().len(), 1);
+
+        let input = input();
+        assert_eq!(
+            input.count_language(42),
+            input.language(42).map(|language| language.len() as u128)
+        );
+        let recursive = input
+            .with_rule(8, "42 | 42 8")
+            .with_rule(11, "42 31 | 42 11 31");
+        assert_eq!(recursive.language(0), None);
+        assert_eq!(recursive.count_language(0), None);
+        let mut rng = Rng::new(19);
+        for _ in 0..20 {
+            let sample = recursive.sample(0, &mut rng).unwrap();
+            assert!(recursive.matches(&sample, 0));
+            let near_miss = recursive.sample_near_miss(0, &mut rng).unwrap();
+            assert!(!recursive.matches(&near_miss, 0));
+        }
+    }
+    #[test]
+    fn test_sample_branching_rules() {
+        // Picking alternatives at random, this would almost always grow forever
+        let rules = parse_input("0: 0 0 0 | 1\n1: \"a\"\n\n");
+        for seed in 0..20 {
+            let sample = rules.sample(0, &mut Rng::new(seed)).unwrap();
+            assert!(sample.chars().all(|c| c == 'a'));
+            // Each expansion of 0 0 0 adds two leaves
+            assert_eq!(sample.len() % 2, 1);
+            assert!(sample.len() <= 3 * MAX_SAMPLE_EXPANSIONS);
+        }
+        // Same with rules which match the empty string
+        let nullable = parse_input("0: 0 0 0 | 1\n1: \"\"\n\n");
+        assert_eq!(nullable.sample(0, &mut Rng::new(0)), Some(String::new()));
+    }
+    #[test]
+    fn test_parse_tree() {
+        let rules = parse_input("0: 1 2 | 0 0\n1: 3 | 1 1\n2: \"b\"\n3: \"a\"\n\n");
+        let tree = rules.parse("aab", 0).unwrap();
+        assert_eq!(
+            tree.render("aab"),
+            "0 [0..3] \"aab\"\n  1 [0..2] \"aa\"\n    1 [0..1] \"a\"\n      3 [0..1] \"a\"\n    1 [1..2] \"a\"\n      3 [1..2] \"a\"\n  2 [2..3] \"b\"\n"
+        );
+        assert_eq!(rules.parse("aba", 0), None);
+        let tree = rules.parse("abab", 0).unwrap();
+        assert_eq!(
+            tree.children
+                .iter()
+                .map(|child| child.span.clone())
+                .collect::<Vec<_>>(),
+            vec![0..2, 2..4]
+        );
+        // Every message that matches has a tree, even with recursive rules
+        let input = input()
+            .with_rule(8, "42 | 42 8")
+            .with_rule(11, "42 31 | 42 11 31");
+        for message in input
+            .messages
+            .iter()
+            .filter(|message| input.matches(message, 0))
+        {
+            assert_eq!(input.parse(message, 0).unwrap().span, 0..message.len());
+        }
+        // Multi-byte terminals: spans are byte offsets, never splitting a character
+        let accents = parse_input("0: 1 2\n1: \"é\"\n2: \"x\"\n\n");
+        assert!(accents.matches("éx", 0));
+        let tree = accents.parse("éx", 0).unwrap();
+        assert_eq!(
+            tree.render("éx"),
+            "0 [0..3] \"éx\"\n  1 [0..2] \"é\"\n  2 [2..3] \"x\"\n"
+        );
+        assert_eq!(accents.parse("xé", 0), None);
+    }
+    #[test]
     fn test_part2() {
         assert_eq!(part2(&input()), 246)
     }
@@ -1,48 +1,73 @@
+use crate::rng::Rng;
 use aoc_runner_derive::{aoc, aoc_generator};
+use itertools::Itertools;
 use radix_fmt;
 use rustc_hash::FxHashMap;
-use std::convert::TryInto;
 use std::fmt::{self, Debug, Formatter};
-use std::iter::{once, repeat};
+use std::fs;
+use std::io;
+use std::iter::once;
+use std::path::Path;
 
-const TILE_SIZE: usize = 10;
+// Edges are packed in a u64
+const MAX_TILE_SIZE: usize = 64;
 
-type TileSlice = [bool; TILE_SIZE];
 type TileId = usize;
 type PermutationId = usize;
+// A tile (by index, not by id) in a given orientation
+type Placement = (usize, PermutationId);
+// Rows of placed tiles
+type Assembly = Vec<Vec<Option<Placement>>>;
 
-// Represent a tile's edge as a binary number
+// Represent a tile's edge as a binary number, along with how many bits it has
 #[derive(Clone, Copy, Eq, PartialEq, Hash)]
-struct CompactEdge(u16);
+struct CompactEdge {
+    bits: u64,
+    len: usize,
+}
 
 impl From<&[bool]> for CompactEdge {
     fn from(slice: &[bool]) -> Self {
-        let mut as_int = 0;
+        assert!(
+            slice.len() <= MAX_TILE_SIZE,
+            "Edges longer than {} are not supported",
+            MAX_TILE_SIZE
+        );
+        let mut bits = 0;
         for (index, bit) in slice.iter().rev().enumerate() {
             if *bit {
-                as_int |= 1 << index;
+                bits |= 1 << index;
             }
         }
-        Self(as_int)
+        Self {
+            bits,
+            len: slice.len(),
+        }
     }
 }
 
 impl Debug for CompactEdge {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", radix_fmt::radix(self.0, 2))
+        write!(
+            f,
+            "{:0>1$}",
+            radix_fmt::radix(self.bits, 2).to_string(),
+            self.len
+        )
     }
 }
 
 impl CompactEdge {
     fn flipped(&self) -> Self {
-        Self(self.0.reverse_bits() >> 16 /*bits*/ - TILE_SIZE)
+        Self {
+            bits: self.bits.reverse_bits() >> (64 - self.len),
+            len: self.len,
+        }
     }
 }
 
 const TOP: usize = 0;
-const RIGHT: usize = 1;
 const BOTTOM: usize = 2;
-const LEFT: usize = 3;
 
 #[derive(Clone)]
 struct CompactTile {
@@ -83,33 +108,27 @@ impl CompactTile {
     fn all_flips(&self) -> impl Iterator<Item = Self> + '_ {
         once(self.clone()).chain(once(self.x_flip()))
     }
+    // Upside down: every edge is now read the other way round
     fn x_flip(&self) -> Self {
         let mut edges = self.edges.clone();
         edges.swap(TOP, BOTTOM);
-        edges[RIGHT] = edges[RIGHT].flipped();
-        edges[LEFT] = edges[LEFT].flipped();
+        for edge in edges.iter_mut() {
+            *edge = edge.flipped();
+        }
         Self { edges }
     }
-    fn left(&self) -> CompactEdge {
-        self.edges[LEFT]
-    }
-    fn top(&self) -> CompactEdge {
-        self.edges[TOP]
-    }
-    fn bottom(&self) -> CompactEdge {
-        self.edges[BOTTOM]
-    }
-    fn right(&self) -> CompactEdge {
-        self.edges[RIGHT]
-    }
 }
 
 #[derive(Clone)]
 struct Tile {
-    data: [TileSlice; TILE_SIZE],
+    // Square: as many rows as columns
+    data: Vec<Vec<bool>>,
 }
 
 impl Tile {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
     fn top(&self) -> CompactEdge {
         self.data[0].as_slice().into()
     }
@@ -117,12 +136,12 @@ impl Tile {
         let right = self
             .data
             .iter()
-            .map(|row| row[TILE_SIZE - 1])
+            .map(|row| row[self.size() - 1])
             .collect::<Vec<_>>();
         right.as_slice().into()
     }
     fn bottom(&self) -> CompactEdge {
-        CompactEdge::from(self.data[TILE_SIZE - 1].as_slice()).flipped()
+        CompactEdge::from(self.data[self.size() - 1].as_slice()).flipped()
     }
     fn left(&self) -> CompactEdge {
         let left = self.data.iter().map(|row| row[0]).collect::<Vec<_>>();
@@ -130,19 +149,18 @@ impl Tile {
     }
     // clockwise rotation
     fn rotate(&self) -> Self {
+        let size = self.size();
         let mut next = self.data.clone();
-        for ii in 0..TILE_SIZE {
-            for jj in 0..TILE_SIZE {
-                next[ii][jj] = self.data[TILE_SIZE - jj - 1][ii];
+        for ii in 0..size {
+            for jj in 0..size {
+                next[ii][jj] = self.data[size - jj - 1][ii];
             }
         }
         Self { data: next }
     }
     fn flip_x(&self) -> Self {
         let mut next = self.data.clone();
-        for ii in 0..TILE_SIZE {
-            next[ii] = self.data[TILE_SIZE - ii - 1];
-        }
+        next.reverse();
         Self { data: next }
     }
     fn flip(&self, num_flips: usize) -> Self {
@@ -167,16 +185,16 @@ impl Tile {
 
 impl From<&str> for Tile {
     fn from(s: &str) -> Self {
-        let vec = s
+        let data = s
             .split_terminator('\n')
-            .map(|row| {
-                let bits = row.chars().map(|c| c == '#').collect::<Vec<_>>();
-                bits.try_into().unwrap()
-            })
-            .collect::<Vec<TileSlice>>();
-        Self {
-            data: vec.try_into().unwrap(),
-        }
+            .map(|row| row.chars().map(|c| c == '#').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert!(
+            data.iter().all(|row| row.len() == data.len()),
+            "Expected a square tile, got:\n{}",
+            s
+        );
+        Self { data }
     }
 }
 
@@ -194,11 +212,17 @@ impl Debug for Tile {
     }
 }
 
-struct Jigsaw {
+pub struct Jigsaw {
+    // Width and height of each tile, borders included
+    tile_size: usize,
     tile_ids: Vec<TileId>,
     tiles: Vec<Tile>,
     compact: Vec<CompactTile>,
-    edge_mapping: FxHashMap<CompactEdge, Vec<usize>>,
+    // permutations[index][perm] is the tile at that index in that orientation
+    permutations: Vec<Vec<CompactTile>>,
+    // by_side[side][edge] are the placements with that edge on that side: the candidates to go
+    // next to a tile
+    by_side: [FxHashMap<CompactEdge, Vec<Placement>>; 4],
 }
 
 impl From<&str> for Jigsaw {
@@ -216,15 +240,42 @@ impl From<&str> for Jigsaw {
                 (id, tile)
             })
             .unzip();
-        let compact = tiles.iter().map(|tile| CompactTile::from(tile)).collect();
-        let mut me = Self {
+        let tile_size = tiles[0].size();
+        assert!(
+            tiles.iter().all(|tile| tile.size() == tile_size),
+            "Expected all tiles to have the same size"
+        );
+        // Tiles need a border on each side and something inside, and edges have to fit in a u64
+        assert!(
+            (3..=MAX_TILE_SIZE).contains(&tile_size),
+            "Expected tiles between 3 and {} cells wide, got {}",
+            MAX_TILE_SIZE,
+            tile_size
+        );
+        let compact = tiles
+            .iter()
+            .map(|tile| CompactTile::from(tile))
+            .collect::<Vec<_>>();
+        let permutations = compact
+            .iter()
+            .map(|tile| tile.all_permutations().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut by_side: [FxHashMap<_, Vec<_>>; 4] = Default::default();
+        for (index, tile_permutations) in permutations.iter().enumerate() {
+            for (perm, tile) in tile_permutations.iter().enumerate() {
+                for (side, edge) in tile.edges.iter().enumerate() {
+                    by_side[side].entry(*edge).or_default().push((index, perm));
+                }
+            }
+        }
+        Self {
+            tile_size,
             tile_ids,
             tiles,
             compact,
-            edge_mapping: FxHashMap::default(),
-        };
-        me.edge_mapping = me.edge_mapping();
-        me
+            permutations,
+            by_side,
+        }
     }
 }
 
@@ -246,198 +297,756 @@ impl Debug for Jigsaw {
     }
 }
 
-impl Jigsaw {
-    // get a mapping from an edge to the tiles that contain it (raw or flipped)
-    // permutations will only come in later
-    fn edge_mapping(&self) -> FxHashMap<CompactEdge, Vec<TileId>> {
-        let mut mapping = FxHashMap::default();
-        for (index, tile) in self.compact.iter().enumerate() {
-            for edge in tile.edges.iter() {
-                let that_edge = mapping.entry(*edge).or_insert(vec![]);
-                that_edge.push(index);
-                let flipped_edge = mapping.entry(edge.flipped()).or_insert(vec![]);
-                flipped_edge.push(index);
-            }
+/// What to include when exporting the assembled jigsaw as an image
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportOptions {
+    // Keep the tile borders, and leave a one pixel gap between tiles
+    pub borders: bool,
+    // List which tile went where, in the file's comments
+    pub tile_ids: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pixel {
+    Blank,
+    Set,
+    // Set, and part of a pattern we were looking for
+    Highlighted,
+    // Between tiles
+    Gap,
+}
+
+impl Pixel {
+    // Same desert theme as the Debug output for tiles, with monsters in sea blue
+    fn colour(&self) -> (u8, u8, u8) {
+        match self {
+            Self::Blank => (255, 165, 0),
+            Self::Set => (139, 90, 43),
+            Self::Highlighted => (0, 105, 148),
+            Self::Gap => (0, 0, 0),
         }
-        mapping
     }
-    fn num_unmatched_edges(&self, tile: &CompactTile) -> usize {
-        tile.edges
+}
+
+// (row, col), relative to where the first tile went
+type Cell = (i32, i32);
+// The cell next to (0, 0) on each side, in the same order as CompactTile::edges
+const OFFSETS: [Cell; 4] = [(-1, 0), (0, 1), (1, 0), (0, -1)];
+
+// The smallest rectangle around some cells, as (top, left, bottom, right), all inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bounds(i32, i32, i32, i32);
+
+impl Bounds {
+    fn with(&self, (row, col): Cell) -> Self {
+        Self(
+            self.0.min(row),
+            self.1.min(col),
+            self.2.max(row),
+            self.3.max(col),
+        )
+    }
+    fn contains(&self, (row, col): Cell) -> bool {
+        (self.0..=self.2).contains(&row) && (self.1..=self.3).contains(&col)
+    }
+    fn height(&self) -> usize {
+        (self.2 - self.0 + 1) as usize
+    }
+    fn width(&self) -> usize {
+        (self.3 - self.1 + 1) as usize
+    }
+}
+
+/// Depth first search growing the jigsaw from its first tile, one neighbouring cell at a time.
+/// Each step picks the undecided cell next to the tiles with the fewest candidates, then tries
+/// every unused tile orientation that fits all its neighbours there, and leaving it empty.
+/// Only exact fits are tried, so edges matching several tiles are no problem: we just backtrack
+/// when a choice leads to a dead end. Tiles only ever go next to other tiles, so assemblies are
+/// all in one piece, and holes can't leave any tile stranded.
+struct Solver<'a> {
+    jigsaw: &'a Jigsaw,
+    rows: usize,
+    cols: usize,
+    // The tile in each cell we decided on, or None if it's empty
+    grid: FxHashMap<Cell, Option<Placement>>,
+    // Cells decided to be empty
+    empty: Vec<Cell>,
+    // How many tiles are next to each cell: those which aren't decided yet are where we can grow
+    neighbours: FxHashMap<Cell, usize>,
+    used: Vec<bool>,
+    num_placed: usize,
+    // Around the placed tiles, after each of them was placed
+    bounds: Vec<Bounds>,
+    // Cells the grid has on top of what the tiles fill
+    max_holes: usize,
+    solutions: Vec<Assembly>,
+    max_solutions: usize,
+}
+
+impl<'a> Solver<'a> {
+    fn tile(&self, (index, perm): Placement) -> &CompactTile {
+        &self.jigsaw.permutations[index][perm]
+    }
+    fn fits(&self, bounds: Bounds) -> bool {
+        bounds.height() <= self.rows && bounds.width() <= self.cols
+    }
+    // Empty cells inside the tiles' bounds are holes, wherever the tiles end up in the grid
+    fn num_holes(&self, bounds: Bounds) -> usize {
+        self.empty
             .iter()
-            .filter(|edge| self.edge_mapping[edge].len() == 1)
+            .filter(|cell| bounds.contains(**cell))
             .count()
     }
+    // Whether the grid can still hold every tile. The tiles' bounds can't grow past an empty cell
+    // right next to them once that would make one hole too many, so they're stuck at their
+    // current height or width when both their sides are.
+    fn has_room(&self) -> bool {
+        let bounds = *self.bounds.last().unwrap();
+        let num_holes = self.num_holes(bounds);
+        let stuck = |side: Bounds| {
+            num_holes == self.max_holes && self.empty.iter().any(|cell| side.contains(*cell))
+        };
+        let Bounds(top, left, bottom, right) = bounds;
+        let height = if stuck(Bounds(top - 1, left, top - 1, right))
+            && stuck(Bounds(bottom + 1, left, bottom + 1, right))
+        {
+            bounds.height()
+        } else {
+            self.rows
+        };
+        let width = if stuck(Bounds(top, left - 1, bottom, left - 1))
+            && stuck(Bounds(top, right + 1, bottom, right + 1))
+        {
+            bounds.width()
+        } else {
+            self.cols
+        };
+        self.jigsaw.tiles.len() + num_holes <= height * width
+    }
+    fn candidates(&self, (row, col): Cell) -> Vec<Placement> {
+        // The edge each neighbouring tile needs on that side
+        let constraints = OFFSETS
+            .iter()
+            .enumerate()
+            .filter_map(
+                |(side, (dr, dc))| match self.grid.get(&(row + dr, col + dc)) {
+                    Some(Some(placed)) => {
+                        Some((side, self.tile(*placed).edges[(side + 2) % 4].flipped()))
+                    }
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        let (side, edge) = constraints[0];
+        let bounds = self.bounds.last().unwrap().with((row, col));
+        if !self.fits(bounds) {
+            return vec![];
+        }
+        self.jigsaw.by_side[side].get(&edge).map_or(vec![], |pool| {
+            pool.iter()
+                .filter(|(index, _)| !self.used[*index])
+                .filter(|placement| {
+                    constraints
+                        .iter()
+                        .all(|(side, edge)| self.tile(**placement).edges[*side] == *edge)
+                })
+                .copied()
+                .collect()
+        })
+    }
+    fn place(&mut self, (row, col): Cell, placement: Placement) {
+        self.grid.insert((row, col), Some(placement));
+        self.used[placement.0] = true;
+        self.num_placed += 1;
+        let bounds = match self.bounds.last() {
+            Some(bounds) => bounds.with((row, col)),
+            None => Bounds(row, col, row, col),
+        };
+        self.bounds.push(bounds);
+        for (dr, dc) in OFFSETS.iter() {
+            *self.neighbours.entry((row + dr, col + dc)).or_default() += 1;
+        }
+    }
+    fn unplace(&mut self, (row, col): Cell, (index, _): Placement) {
+        for (dr, dc) in OFFSETS.iter() {
+            let neighbour = (row + dr, col + dc);
+            let count = self.neighbours.get_mut(&neighbour).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.neighbours.remove(&neighbour);
+            }
+        }
+        self.bounds.pop();
+        self.num_placed -= 1;
+        self.used[index] = false;
+        self.grid.remove(&(row, col));
+    }
+    // The tiles as placed so far, in every position they fit in the grid
+    fn add_solutions(&mut self) {
+        let bounds = *self.bounds.last().unwrap();
+        for row_offset in 0..=self.rows - bounds.height() {
+            for col_offset in 0..=self.cols - bounds.width() {
+                if self.solutions.len() >= self.max_solutions {
+                    return;
+                }
+                let mut assembly = vec![vec![None; self.cols]; self.rows];
+                for ((row, col), placed) in self.grid.iter() {
+                    if let Some(placed) = placed {
+                        assembly[(row - bounds.0) as usize + row_offset]
+                            [(col - bounds.1) as usize + col_offset] = Some(*placed);
+                    }
+                }
+                self.solutions.push(assembly);
+            }
+        }
+    }
+    fn search(&mut self) {
+        if self.solutions.len() >= self.max_solutions {
+            return;
+        }
+        if self.num_placed == self.jigsaw.tiles.len() {
+            self.add_solutions();
+            return;
+        }
+        if !self.has_room() {
+            return;
+        }
+        // Out of places to grow, with tiles left over: they'd have to be apart from the others
+        let (cell, candidates) = match self
+            .neighbours
+            .keys()
+            .filter(|cell| !self.grid.contains_key(cell))
+            .map(|cell| (*cell, self.candidates(*cell)))
+            .min_by_key(|(cell, candidates)| (candidates.len(), *cell))
+        {
+            Some(next) => next,
+            None => return,
+        };
+        for placement in candidates {
+            self.place(cell, placement);
+            if self.num_holes(*self.bounds.last().unwrap()) <= self.max_holes {
+                self.search();
+            }
+            self.unplace(cell, placement);
+        }
+        // Or leave it empty, if that's not one hole too many
+        let bounds = *self.bounds.last().unwrap();
+        if !bounds.contains(cell) || self.num_holes(bounds) < self.max_holes {
+            self.grid.insert(cell, None);
+            self.empty.push(cell);
+            self.search();
+            self.empty.pop();
+            self.grid.remove(&cell);
+        }
+    }
+}
 
-    fn is_corner(&self, tile: &CompactTile) -> bool {
-        self.num_unmatched_edges(tile) == 2
+impl Jigsaw {
+    /// The (rows, cols) rectangles that all the tiles fill exactly, squarest first
+    pub fn layouts(&self) -> Vec<(usize, usize)> {
+        let num_tiles = self.tiles.len();
+        let mut layouts = (1..=num_tiles)
+            .filter(|rows| num_tiles % rows == 0)
+            .map(|rows| (rows, num_tiles / rows))
+            .collect::<Vec<_>>();
+        layouts.sort_by_key(|(rows, cols)| (rows.max(cols) - rows.min(cols), *rows));
+        layouts
     }
-    // Find the permutation of the tile "id", which sits to the right of "left" and to the
-    // bottom of "top"
-    fn find_permutation(
+    /// Up to `max_solutions` ways to assemble the jigsaw into a full rectangle, of any shape. An
+    /// empty result means there are none.
+    /// Note that any assembly also comes flipped and rotated: that's 8 solutions for the price of
+    /// one. Rotating a rectangle swaps its rows and columns, so those are 4 of each shape.
+    pub fn solve(&self, max_solutions: usize) -> Vec<Assembly> {
+        let mut solutions = vec![];
+        for (rows, cols) in self.layouts() {
+            solutions.extend(self.solve_in(rows, cols, max_solutions - solutions.len()));
+            if solutions.len() >= max_solutions {
+                break;
+            }
+        }
+        solutions
+    }
+    /// Up to `max_solutions` ways to assemble the jigsaw in a `rows` x `cols` grid. Cells left
+    /// over when there are fewer tiles than that are holes: see `holes`. The tiles always hang
+    /// together in one piece.
+    pub fn solve_in(&self, rows: usize, cols: usize, max_solutions: usize) -> Vec<Assembly> {
+        if rows * cols < self.tiles.len() {
+            return vec![];
+        }
+        let mut solver = Solver {
+            jigsaw: self,
+            rows,
+            cols,
+            grid: FxHashMap::default(),
+            empty: vec![],
+            neighbours: FxHashMap::default(),
+            used: vec![false; self.tiles.len()],
+            num_placed: 0,
+            bounds: vec![],
+            max_holes: rows * cols - self.tiles.len(),
+            solutions: vec![],
+            max_solutions,
+        };
+        // Every assembly has the first tile somewhere, in one of its orientations
+        for perm in 0..8 {
+            solver.place((0, 0), (0, perm));
+            solver.search();
+            solver.unplace((0, 0), (0, perm));
+        }
+        solver.solutions
+    }
+    /// A full rectangle if the tiles make one, or else the squarest grid they fit in, with holes
+    /// where tiles are missing. None if the tiles don't fit together at all.
+    pub fn assemble_jigsaw(&self) -> Option<Assembly> {
+        self.solve(1).pop().or_else(|| {
+            let num_tiles = self.tiles.len();
+            let cols = (1..).find(|cols| cols * cols >= num_tiles)?;
+            self.solve_in(num_tiles.div_ceil(cols), cols, 1).pop()
+        })
+    }
+    fn picture(&self) -> Option<Vec<Vec<bool>>> {
+        Some(self.picture_of(&self.assemble_jigsaw()?))
+    }
+    /// The assembled tiles, stripped of their borders. Holes are left blank.
+    pub fn picture_of(&self, assembled: &Assembly) -> Vec<Vec<bool>> {
+        let inner = self.tile_size - 2;
+        let mut output = vec![vec![false; assembled[0].len() * inner]; assembled.len() * inner];
+        for (row_index, row) in assembled.iter().enumerate() {
+            for (col_index, placement) in row.iter().enumerate() {
+                if let Some((index, perm)) = placement {
+                    let tile = self.tiles[*index].with_permutation(*perm);
+                    for (tile_row_index, tile_row) in tile.data[1..=inner].iter().enumerate() {
+                        output[row_index * inner + tile_row_index]
+                            [col_index * inner..(col_index + 1) * inner]
+                            .copy_from_slice(&tile_row[1..=inner]);
+                    }
+                }
+            }
+        }
+        output
+    }
+    // What to draw at each pixel of an exported image
+    fn canvas(
         &self,
-        index: usize,
-        left: Option<usize>,
-        top: Option<usize>,
-    ) -> PermutationId {
-        let tile = self.compact[index].clone();
-        let permutation = tile
-            .all_permutations()
-            .enumerate()
-            .find_map(move |(perm_id, perm)| {
-                let mut top_neighbours = self.edge_mapping[&perm.top()]
-                    .iter()
-                    .filter(|t| **t != index);
-                let top_matches = if top.is_none() {
-                    top_neighbours.next().is_none()
-                } else {
-                    top_neighbours.next().copied() == top && top_neighbours.next().is_none()
-                };
-                let mut left_neighbours = self.edge_mapping[&perm.left()]
-                    .iter()
-                    .filter(|t| **t != index);
-                let left_matches = if left.is_none() {
-                    left_neighbours.next().is_none()
-                } else {
-                    left_neighbours.next().copied() == left && left_neighbours.next().is_none()
-                };
-                if top_matches && left_matches {
-                    Some(perm_id)
-                } else {
-                    None
+        assembled: &Assembly,
+        options: ExportOptions,
+        pattern: Option<&Pattern>,
+    ) -> Vec<Vec<Pixel>> {
+        let picture = self.picture_of(assembled);
+        let covered = pattern.map(|pattern| pattern.covered(&picture));
+        let pixel = |row: usize, col: usize| {
+            if covered.as_ref().is_some_and(|covered| covered[row][col]) {
+                Pixel::Highlighted
+            } else if picture[row][col] {
+                Pixel::Set
+            } else {
+                Pixel::Blank
+            }
+        };
+        if !options.borders {
+            return (0..picture.len())
+                .map(|row| (0..picture[row].len()).map(|col| pixel(row, col)).collect())
+                .collect();
+        }
+        // Full tiles, one pixel apart
+        let size = self.tile_size;
+        let inner = size - 2;
+        let height = assembled.len() * (size + 1) - 1;
+        let width = assembled[0].len() * (size + 1) - 1;
+        let mut canvas = vec![vec![Pixel::Gap; width]; height];
+        for (tile_row, row) in assembled.iter().enumerate() {
+            for (tile_col, (index, perm)) in row
+                .iter()
+                .enumerate()
+                .filter_map(|(tile_col, placement)| placement.map(|placed| (tile_col, placed)))
+            {
+                let tile = self.tiles[index].with_permutation(perm);
+                for (ii, data_row) in tile.data.iter().enumerate() {
+                    for (jj, cell) in data_row.iter().enumerate() {
+                        let is_border = ii == 0 || jj == 0 || ii == size - 1 || jj == size - 1;
+                        canvas[tile_row * (size + 1) + ii][tile_col * (size + 1) + jj] =
+                            if is_border {
+                                if *cell {
+                                    Pixel::Set
+                                } else {
+                                    Pixel::Blank
+                                }
+                            } else {
+                                pixel(tile_row * inner + ii - 1, tile_col * inner + jj - 1)
+                            };
+                    }
                 }
-            })
-            .expect("Expected to be able to permut the corner into a top left position");
-        permutation
+            }
+        }
+        canvas
     }
-    fn tile_with_shared_edge(&self, edge: CompactEdge, tile_id: TileId) -> Option<TileId> {
-        self.edge_mapping[&edge]
-            .iter()
-            .filter(|id| **id != tile_id)
-            .next()
-            .copied()
+    // Netpbm header: magic number, comments and dimensions
+    fn netpbm_header(
+        &self,
+        magic: &str,
+        assembled: &Assembly,
+        options: ExportOptions,
+        canvas: &[Vec<Pixel>],
+    ) -> String {
+        let mut header = format!("{}\n", magic);
+        if options.tile_ids {
+            for (tile_row, row) in assembled.iter().enumerate() {
+                for (tile_col, placement) in row.iter().enumerate() {
+                    header.push_str(&match placement {
+                        Some((index, perm)) => format!(
+                            "# Tile {} at row {}, column {}, orientation {}\n",
+                            self.tile_ids[*index], tile_row, tile_col, perm
+                        ),
+                        None => format!("# Hole at row {}, column {}\n", tile_row, tile_col),
+                    });
+                }
+            }
+        }
+        header.push_str(&format!("{} {}\n", canvas[0].len(), canvas.len()));
+        header
+    }
+    /// The assembled image as a plain PBM (black and white) file, if the jigsaw can be assembled
+    pub fn to_pbm(&self, options: ExportOptions) -> Option<String> {
+        let assembled = self.assemble_jigsaw()?;
+        let canvas = self.canvas(&assembled, options, None);
+        let mut out = self.netpbm_header("P1", &assembled, options, &canvas);
+        for row in canvas.iter() {
+            let bits = row
+                .iter()
+                .map(|pixel| match pixel {
+                    Pixel::Set | Pixel::Highlighted => "1",
+                    Pixel::Blank | Pixel::Gap => "0",
+                })
+                .collect::<Vec<_>>();
+            out.push_str(&plain_netpbm_lines(&bits, ""));
+        }
+        Some(out)
     }
-    fn get_tile(&self, index: usize, perm: PermutationId) -> Option<CompactTile> {
-        self.compact[index].all_permutations().nth(perm)
+    /// The assembled image as a plain PPM (colour) file, with the cells of any match of the
+    /// pattern highlighted. None if the jigsaw can't be assembled.
+    pub fn to_ppm(&self, options: ExportOptions, pattern: Option<&Pattern>) -> Option<String> {
+        let assembled = self.assemble_jigsaw()?;
+        let canvas = self.canvas(&assembled, options, pattern);
+        let mut out = self.netpbm_header("P3", &assembled, options, &canvas);
+        out.push_str("255\n");
+        for row in canvas.iter() {
+            let colours = row
+                .iter()
+                .map(|pixel| {
+                    let (r, g, b) = pixel.colour();
+                    format!("{} {} {}", r, g, b)
+                })
+                .collect::<Vec<_>>();
+            out.push_str(&plain_netpbm_lines(&colours, " "));
+        }
+        Some(out)
     }
-    fn find_tile(
+    /// Write the assembled image to a plain PBM file, as given by `to_pbm`
+    pub fn write_pbm(&self, path: impl AsRef<Path>, options: ExportOptions) -> io::Result<()> {
+        let pbm = self.to_pbm(options).ok_or_else(cannot_assemble)?;
+        fs::write(path, pbm)
+    }
+    /// Write the assembled image to a plain PPM file, as given by `to_ppm`
+    pub fn write_ppm(
         &self,
-        left: Option<(usize, PermutationId)>,
-        top: Option<(usize, PermutationId)>,
-    ) -> (TileId, PermutationId) {
-        let index = if left.is_none() && top.is_none() {
-            self.compact
+        path: impl AsRef<Path>,
+        options: ExportOptions,
+        pattern: Option<&Pattern>,
+    ) -> io::Result<()> {
+        let ppm = self.to_ppm(options, pattern).ok_or_else(cannot_assemble)?;
+        fs::write(path, ppm)
+    }
+}
+
+fn cannot_assemble() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Couldn't assemble the jigsaw")
+}
+
+// Plain Netpbm files shouldn't have lines longer than 70 characters
+fn plain_netpbm_lines<S: AsRef<str>>(values: &[S], separator: &str) -> String {
+    let mut out = String::new();
+    let mut line = String::new();
+    for value in values.iter().map(|value| value.as_ref()) {
+        if !line.is_empty() && line.len() + separator.len() + value.len() > 70 {
+            out.push_str(&line);
+            out.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push_str(separator);
+        }
+        line.push_str(value);
+    }
+    out.push_str(&line);
+    out.push('\n');
+    out
+}
+
+/// The pattern to look for in part 2
+pub const SEA_MONSTER: &str = "                  # \n#    ##    ##    ###\n #  #  #  #  #  #   ";
+
+/// The set cells of an ASCII pattern, like a sea monster: '#' for set, anything else for blank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    // (row, col), sorted
+    cells: Vec<(usize, usize)>,
+    height: usize,
+    width: usize,
+}
+
+impl From<&str> for Pattern {
+    fn from(s: &str) -> Self {
+        let lines = s.lines().collect::<Vec<_>>();
+        let cells = lines
+            .iter()
+            .enumerate()
+            .flat_map(|(row_index, row)| {
+                row.chars().enumerate().filter_map(move |(col_index, c)| {
+                    if c == '#' {
+                        Some((row_index, col_index))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+        Self {
+            cells,
+            height: lines.len(),
+            width: lines
                 .iter()
-                .position(|tile| self.is_corner(tile))
-                .expect("Expected to find a corner tile")
-        } else if top.is_none() {
-            let (left_index, perm) = left.unwrap();
-            let left_tile = self.get_tile(left_index, perm).unwrap();
-            self.tile_with_shared_edge(left_tile.right(), left_index)
-                .unwrap()
-        } else {
-            let (top_index, perm) = top.unwrap();
-            let top_tile = self.get_tile(top_index, perm).unwrap();
-            self.tile_with_shared_edge(top_tile.bottom(), top_index)
-                .unwrap()
-        };
-        (
-            index,
-            self.find_permutation(
-                index,
-                left.map(|(index, _)| index),
-                top.map(|(index, _)| index),
-            ),
+                .map(|line| line.chars().count())
+                .max()
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Where a pattern was found: the top left corner of the pattern, in the given orientation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternMatch {
+    pub orientation: PermutationId,
+    pub row: usize,
+    pub col: usize,
+}
+
+impl Pattern {
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(fs::read_to_string(path)?.as_str().into())
+    }
+    fn with_cells(
+        cells: impl Iterator<Item = (usize, usize)>,
+        height: usize,
+        width: usize,
+    ) -> Self {
+        let mut cells = cells.collect::<Vec<_>>();
+        cells.sort_unstable();
+        Self {
+            cells,
+            height,
+            width,
+        }
+    }
+    // clockwise rotation, like Tile::rotate
+    fn rotate(&self) -> Self {
+        Self::with_cells(
+            self.cells.iter().map(|(r, c)| (*c, self.height - r - 1)),
+            self.width,
+            self.height,
         )
     }
-    // It appears all edges are unique, which is allows us to be naive
-    fn assemble_jigsaw(&self) -> Vec<Vec<(TileId, PermutationId)>> {
-        let mut assembled = Vec::<Vec<(TileId, PermutationId)>>::new();
-        let mut current_row = vec![];
-        let mut col_index = 0;
-        loop {
-            let top = assembled.last().map(|last_row| last_row[col_index]);
-            let left = current_row.last().copied();
-            let (index, perm) = self.find_tile(left, top);
-            let right = self.get_tile(index, perm).map(|x| x.right()).unwrap();
-            let bottom = self.get_tile(index, perm).map(|x| x.bottom()).unwrap();
-            current_row.push((index, perm));
-            if self.tile_with_shared_edge(right, index).is_some() {
-                col_index += 1;
-            } else if self.tile_with_shared_edge(bottom, index).is_some() {
-                assembled.push(current_row.clone());
-                current_row = vec![];
-                col_index = 0;
-            } else {
-                assembled.push(current_row.clone());
-                return assembled;
+    // upside down, like Tile::flip_x
+    fn flip_x(&self) -> Self {
+        Self::with_cells(
+            self.cells.iter().map(|(r, c)| (self.height - r - 1, *c)),
+            self.height,
+            self.width,
+        )
+    }
+    /// The pattern in all 8 orientations, numbered like Tile::with_permutation.
+    /// Symmetric patterns look the same in several orientations: only the first one is kept, so
+    /// that the same cells are never reported as two different matches.
+    pub fn orientations(&self) -> Vec<(PermutationId, Pattern)> {
+        let mut orientations = Vec::<(PermutationId, Pattern)>::new();
+        let mut rotated = Self::with_cells(self.cells.iter().copied(), self.height, self.width);
+        for num_rotations in 0..4 {
+            for (num_flips, pattern) in [rotated.clone(), rotated.flip_x()].iter().enumerate() {
+                if orientations.iter().all(|(_, seen)| seen != pattern) {
+                    orientations.push((num_rotations * 2 + num_flips, pattern.clone()));
+                }
             }
+            rotated = rotated.rotate();
         }
+        orientations
     }
-    fn picture(&self) -> Vec<Vec<bool>> {
-        let mut output = Vec::<Vec<bool>>::new();
-        for (row_index, row) in self.assemble_jigsaw().iter().enumerate() {
-            for (col_index, (index, perm)) in row.iter().enumerate() {
-                let tile = self.tiles[*index].with_permutation(*perm);
-                for (tile_row_index, tile_row) in tile.data.iter().enumerate() {
-                    if tile_row_index != 0 && tile_row_index != TILE_SIZE - 1 {
-                        if col_index == 0 {
-                            output.push(vec![]);
-                        }
-                        output[row_index * (TILE_SIZE - 2) + (tile_row_index - 1)]
-                            .extend_from_slice(&tile_row[1..(TILE_SIZE - 1)]);
+    /// Every place the pattern appears in the image, in any orientation. Matches may overlap.
+    pub fn find(&self, image: &[Vec<bool>]) -> Vec<PatternMatch> {
+        let image_height = image.len();
+        let image_width = image.first().map_or(0, |row| row.len());
+        let mut matches = vec![];
+        for (orientation, pattern) in self.orientations() {
+            if pattern.height > image_height || pattern.width > image_width {
+                continue;
+            }
+            for row in 0..=(image_height - pattern.height) {
+                for col in 0..=(image_width - pattern.width) {
+                    if pattern.cells.iter().all(|(r, c)| image[row + r][col + c]) {
+                        matches.push(PatternMatch {
+                            orientation,
+                            row,
+                            col,
+                        });
                     }
                 }
             }
         }
+        matches
+    }
+    /// The image cells that are part of at least one match. Cells shared by overlapping matches
+    /// are only set once.
+    pub fn covered(&self, image: &[Vec<bool>]) -> Vec<Vec<bool>> {
+        let orientations = self.orientations();
+        let mut output = image
+            .iter()
+            .map(|row| vec![false; row.len()])
+            .collect::<Vec<_>>();
+        for found in self.find(image) {
+            let (_, pattern) = orientations
+                .iter()
+                .find(|(orientation, _)| *orientation == found.orientation)
+                .unwrap();
+            for (r, c) in pattern.cells.iter() {
+                output[found.row + r][found.col + c] = true;
+            }
+        }
         output
     }
 }
 
-fn sea_monsters(sea: &[Vec<bool>]) -> Vec<Vec<bool>> {
-    let monster = [
-        "                  # ",
-        "#    ##    ##    ###",
-        " #  #  #  #  #  #   ",
-    ];
-    let n_rows = monster.len();
-    let n_cols = monster[0].chars().count();
-    let sea_monster = monster
+/// The (row, col) of every cell left empty in an assembly
+pub fn holes(assembled: &Assembly) -> Vec<(usize, usize)> {
+    assembled
         .iter()
         .enumerate()
-        .flat_map(|(row_index, row)| {
-            row.chars().enumerate().filter_map(move |(col_index, c)| {
-                if c == '#' {
-                    Some((row_index, col_index))
-                } else {
-                    None
-                }
-            })
+        .flat_map(|(row, placements)| {
+            placements
+                .iter()
+                .enumerate()
+                .filter(|(_, placement)| placement.is_none())
+                .map(move |(col, _)| (row, col))
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    let mut output = repeat(repeat(false).take(sea[0].len()).collect::<Vec<_>>())
-        .take(sea.len())
+/// Cut an image into a shuffled jigsaw of `tile_size` tiles, in the puzzle input format.
+/// Tile borders are random but shared between neighbours, and each tile is randomly flipped and
+/// rotated, so the jigsaw assembles back into the image (in one of its 8 orientations).
+pub fn cut_jigsaw(image: &[Vec<bool>], tile_size: usize, rng: &mut Rng) -> String {
+    assert!(
+        (3..=MAX_TILE_SIZE).contains(&tile_size),
+        "Expected a tile size between 3 and {}",
+        MAX_TILE_SIZE
+    );
+    let inner = tile_size - 2;
+    let height = image.len();
+    let width = image.first().map_or(0, |row| row.len());
+    assert!(
+        height > 0
+            && height % inner == 0
+            && width % inner == 0
+            && image.iter().all(|row| row.len() == width),
+        "Expected a rectangular image with sides a multiple of {}",
+        inner
+    );
+    let (rows, cols) = (height / inner, width / inner);
+    // Neighbours share a border: lay the tiles out on a lattice where every other line is one
+    let step = tile_size - 1;
+    let mut lattice = vec![vec![false; cols * step + 1]; rows * step + 1];
+    for (r, lattice_row) in lattice.iter_mut().enumerate() {
+        for (c, cell) in lattice_row.iter_mut().enumerate() {
+            *cell = if r % step == 0 || c % step == 0 {
+                rng.below(2) == 1
+            } else {
+                image[r - r / step - 1][c - c / step - 1]
+            };
+        }
+    }
+    let mut tiles = (0..rows)
+        .cartesian_product(0..cols)
+        .map(|(row, col)| {
+            let data = lattice[row * step..=(row + 1) * step]
+                .iter()
+                .map(|lattice_row| lattice_row[col * step..=(col + 1) * step].to_vec())
+                .collect();
+            Tile { data }.with_permutation(rng.below(8))
+        })
         .collect::<Vec<_>>();
-    for row in 0..(sea.len() - n_rows) {
-        for col in 0..(sea[0].len() - n_cols) {
-            if sea_monster.iter().all(|(r, c)| sea[row + r][col + c]) {
-                sea_monster
-                    .iter()
-                    .for_each(|(r, c)| output[row + r][col + c] = true);
-            }
+    // Fisher-Yates
+    for ii in (1..tiles.len()).rev() {
+        tiles.swap(ii, rng.below(ii + 1));
+    }
+    // Four digit ids like the puzzle's, as long as there are enough of them
+    let id_range = 9000.max(10 * tiles.len());
+    let mut tile_ids = Vec::<TileId>::new();
+    while tile_ids.len() < tiles.len() {
+        let id = 1000 + rng.below(id_range);
+        if !tile_ids.contains(&id) {
+            tile_ids.push(id);
         }
     }
-    output
+    tile_ids
+        .iter()
+        .zip(tiles.iter())
+        .map(|(id, tile)| {
+            let rows = tile
+                .data
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|cell| if *cell { '#' } else { '.' })
+                        .collect::<String>()
+                })
+                .join("\n");
+            format!("Tile {}:\n{}\n", id, rows)
+        })
+        .join("\n")
 }
 
-fn rotate(data: &[Vec<bool>]) -> Vec<Vec<bool>> {
-    let mut next = data.iter().cloned().collect::<Vec<_>>();
-    for ii in 0..data.len() {
-        for jj in 0..data[0].len() {
-            next[ii][jj] = data[data[0].len() - jj - 1][ii];
-        }
+/// Read an image back from a plain PBM file, like the ones `Jigsaw::to_pbm` writes
+pub fn read_pbm(s: &str) -> Result<Vec<Vec<bool>>, String> {
+    let mut tokens = s
+        .lines()
+        .map(|line| line.split('#').next().unwrap())
+        .flat_map(|line| line.split_whitespace());
+    if tokens.next() != Some("P1") {
+        return Err("Expected a plain PBM file, starting with P1".to_string());
+    }
+    let mut dimension = |name| {
+        tokens
+            .next()
+            .and_then(|token| token.parse::<usize>().ok())
+            .filter(|size| *size > 0)
+            .ok_or_else(|| format!("Couldn't parse the image {}", name))
+    };
+    let width = dimension("width")?;
+    let height = dimension("height")?;
+    // Pixels needn't be separated by whitespace
+    let pixels = tokens
+        .flat_map(|token| token.chars())
+        .map(|c| match c {
+            '0' => Ok(false),
+            '1' => Ok(true),
+            _ => Err(format!("Unexpected pixel value {:?}", c)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if pixels.len() != width * height {
+        return Err(format!(
+            "Expected {} pixels, got {}",
+            width * height,
+            pixels.len()
+        ));
     }
-    next
+    Ok(pixels.chunks(width).map(|row| row.to_vec()).collect())
 }
 
 #[aoc_generator(day20)]
@@ -447,8 +1056,10 @@ fn parse_input(s: &str) -> Jigsaw {
 
 #[aoc(day20, part1)]
 fn part1(jig: &Jigsaw) -> usize {
-    let assembled = jig.assemble_jigsaw();
-    let first_row = assembled[0].clone();
+    let assembled = jig
+        .assemble_jigsaw()
+        .expect("Expected the jigsaw to have a solution");
+    let first_row = &assembled[0];
     let last_row = assembled.last().unwrap();
     [
         first_row.first(),
@@ -457,31 +1068,31 @@ fn part1(jig: &Jigsaw) -> usize {
         last_row.last(),
     ]
     .iter()
-    .map(|maybe| jig.tile_ids[maybe.unwrap().0])
+    .map(|corner| {
+        let (index, _) = corner
+            .copied()
+            .flatten()
+            .expect("Expected a tile in every corner");
+        jig.tile_ids[index]
+    })
     .product()
 }
 
 #[aoc(day20, part2)]
 fn part2(jig: &Jigsaw) -> usize {
-    let mut picture = jig.picture();
+    let picture = jig
+        .picture()
+        .expect("Expected the jigsaw to have a solution");
     let num_cells = picture.iter().flatten().filter(|cell| **cell).count();
-    let mut num_monster_cells = 0;
-    let mut n_rotations = 0;
-    while num_monster_cells == 0 && n_rotations < 4 {
-        n_rotations += 1;
-        let sea_monsters = sea_monsters(&picture);
-        num_monster_cells = sea_monsters.iter().flatten().filter(|cell| **cell).count();
-        if num_monster_cells == 0 {
-            // Note: found them with only rotation and no flip, so didn't bother flipping
-            picture = rotate(&picture);
-        }
-    }
+    let sea_monsters = Pattern::from(SEA_MONSTER).covered(&picture);
+    let num_monster_cells = sea_monsters.iter().flatten().filter(|cell| **cell).count();
     num_cells - num_monster_cells
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::iter::repeat;
     fn input() -> Jigsaw {
         parse_input(include_str!("../input/2020/day20.txt"))
     }
@@ -596,6 +1207,321 @@ Tile 3079:
     fn test_example() {
         assert_eq!(20899048083289, part1(&parse_input(EXAMPLE)))
     }
+    // Widen the tiles by repeating every row and column but the borders
+    fn scaled(s: &str, factor: usize) -> String {
+        s.split("\n\n")
+            .map(|tile| {
+                let lines = tile.lines().collect::<Vec<_>>();
+                let scale_line = |line: &str| {
+                    let chars = line.chars().collect::<Vec<_>>();
+                    once(chars[0])
+                        .chain(
+                            chars[1..chars.len() - 1]
+                                .iter()
+                                .flat_map(|c| repeat(*c).take(factor)),
+                        )
+                        .chain(once(chars[chars.len() - 1]))
+                        .collect::<String>()
+                };
+                once(lines[0].to_string())
+                    .chain(once(scale_line(lines[1])))
+                    .chain(
+                        lines[2..lines.len() - 1]
+                            .iter()
+                            .flat_map(|line| repeat(scale_line(line)).take(factor)),
+                    )
+                    .chain(once(scale_line(lines[lines.len() - 1])))
+                    .join("\n")
+            })
+            .join("\n\n")
+    }
+    #[test]
+    fn test_tile_sizes() {
+        for factor in [1, 2, 4, 7].iter() {
+            let jigsaw = parse_input(&scaled(EXAMPLE, *factor));
+            assert_eq!(jigsaw.tile_size, 8 * factor + 2);
+            assert_eq!(20899048083289, part1(&jigsaw));
+            assert_eq!(jigsaw.picture().unwrap().len(), 3 * 8 * factor);
+        }
+    }
+    #[test]
+    fn test_compact_permutations() {
+        let jigsaw = parse_input(EXAMPLE);
+        for (tile, permutations) in jigsaw.tiles.iter().zip(jigsaw.permutations.iter()) {
+            for (perm, compact) in permutations.iter().enumerate() {
+                let permuted = CompactTile::from(&tile.with_permutation(perm));
+                assert_eq!(permuted.edges, compact.edges);
+            }
+        }
+    }
+    #[test]
+    fn test_all_solutions() {
+        // One solution, in 8 orientations
+        assert_eq!(parse_input(EXAMPLE).solve(usize::MAX).len(), 8);
+        // The middle tile doesn't fit anymore
+        let broken = EXAMPLE.replace("Tile 1427:\n###.##.#..", "Tile 1427:\n#.#.##.#..");
+        assert!(parse_input(&broken).solve(usize::MAX).is_empty());
+        // Blank tiles fit anywhere, in any orientation
+        let blank = (1..=4)
+            .map(|id| format!("Tile {}:\n...\n...\n...", id))
+            .join("\n\n");
+        let jigsaw = parse_input(&blank);
+        assert_eq!(
+            jigsaw.solve_in(2, 2, usize::MAX).len(),
+            4 * 3 * 2 * 8 * 8 * 8 * 8
+        );
+        // ... and in a line too
+        assert_eq!(jigsaw.layouts(), vec![(2, 2), (1, 4), (4, 1)]);
+        assert_eq!(jigsaw.solve(5).len(), 5);
+    }
+    #[test]
+    fn test_pattern() {
+        let image = |s: &str| {
+            s.lines()
+                .map(|line| line.chars().map(|c| c == '#').collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        let pattern = Pattern::from("##");
+        // Horizontal or vertical: flipping a line gives the same line back
+        assert_eq!(pattern.orientations().len(), 2);
+        let found = pattern.find(&image("###\n..#"));
+        assert_eq!(
+            found,
+            vec![
+                PatternMatch {
+                    orientation: 0,
+                    row: 0,
+                    col: 0
+                },
+                PatternMatch {
+                    orientation: 0,
+                    row: 0,
+                    col: 1
+                },
+                PatternMatch {
+                    orientation: 2,
+                    row: 0,
+                    col: 2
+                },
+            ]
+        );
+        // The overlapping cells only count once
+        assert_eq!(pattern.covered(&image("###\n..#")), image("###\n..#"));
+        let corner = Pattern::from("#.\n##");
+        assert_eq!(corner.orientations().len(), 4);
+        assert_eq!(corner.find(&image("##\n#.")).len(), 1);
+        assert!(corner.find(&image("#")).is_empty());
+        // Monsters can be found whichever way the picture is flipped
+        let picture = input().picture().unwrap();
+        let flipped = picture.iter().rev().cloned().collect::<Vec<_>>();
+        let monster = Pattern::from(SEA_MONSTER);
+        assert_eq!(monster.orientations().len(), 8);
+        assert_eq!(monster.find(&picture).len(), monster.find(&flipped).len());
+        assert!(!monster.find(&flipped).is_empty());
+    }
+    #[test]
+    fn test_export() {
+        let jigsaw = parse_input(EXAMPLE);
+        let pbm = jigsaw.to_pbm(ExportOptions::default()).unwrap();
+        assert!(pbm.starts_with("P1\n24 24\n"));
+        let num_set = jigsaw
+            .picture()
+            .unwrap()
+            .iter()
+            .flatten()
+            .filter(|cell| **cell)
+            .count();
+        assert_eq!(
+            pbm.lines()
+                .skip(2)
+                .flat_map(|line| line.chars())
+                .filter(|c| *c == '1')
+                .count(),
+            num_set
+        );
+        assert!(pbm.lines().all(|line| line.len() <= 70));
+
+        let options = ExportOptions {
+            borders: true,
+            tile_ids: true,
+        };
+        let pbm = jigsaw.to_pbm(options).unwrap();
+        let header = pbm.lines().take(11).collect::<Vec<_>>();
+        assert_eq!(header[0], "P1");
+        assert!(header[1..10].iter().all(|line| line.starts_with("# Tile ")));
+        assert_eq!(header[10], "32 32");
+
+        let jigsaw = input();
+        let monster = Pattern::from(SEA_MONSTER);
+        let ppm = jigsaw.to_ppm(options, Some(&monster)).unwrap();
+        assert!(ppm.lines().all(|line| line.len() <= 70));
+        let num_highlighted = ppm
+            .lines()
+            .skip_while(|line| *line != "255")
+            .skip(1)
+            .flat_map(|line| line.split(' '))
+            .collect::<Vec<_>>()
+            .chunks(3)
+            .filter(|rgb| *rgb == ["0", "105", "148"])
+            .count();
+        let num_monster_cells = monster
+            .covered(&jigsaw.picture().unwrap())
+            .iter()
+            .flatten()
+            .filter(|cell| **cell)
+            .count();
+        assert_eq!(num_highlighted, num_monster_cells);
+
+        let path = std::env::temp_dir().join("day20_test_export.ppm");
+        jigsaw.write_ppm(&path, options, Some(&monster)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), ppm);
+        fs::remove_file(&path).unwrap();
+        let broken = EXAMPLE.replace("Tile 1427:\n###.##.#..", "Tile 1427:\n#.#.##.#..");
+        let error = parse_input(&broken)
+            .write_pbm(&path, ExportOptions::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!path.exists());
+    }
+    #[test]
+    fn test_cut_jigsaw() {
+        let picture = input().picture().unwrap();
+        let mut rng = Rng::new(20);
+        for tile_size in [10, 14, 26].iter() {
+            let jigsaw = parse_input(&cut_jigsaw(&picture, *tile_size, &mut rng));
+            let side = 96 / (tile_size - 2);
+            assert_eq!(jigsaw.tile_size, *tile_size);
+            assert_eq!(jigsaw.tiles.len(), side * side);
+            let reassembled = Tile {
+                data: jigsaw.picture().unwrap(),
+            };
+            assert!((0..8).any(|perm| reassembled.with_permutation(perm).data == picture));
+        }
+        // Images can come from PBM files too
+        let pbm = input().to_pbm(ExportOptions::default()).unwrap();
+        assert_eq!(read_pbm(&pbm), Ok(picture));
+        assert_eq!(
+            read_pbm("P1\n# comment\n3 2\n010\n1 1 1"),
+            Ok(vec![vec![false, true, false], vec![true, true, true]])
+        );
+        assert!(read_pbm("P3\n3 2\n010\n111").is_err());
+        assert!(read_pbm("P1\n3 2\n010\n11").is_err());
+    }
+    #[test]
+    fn test_layouts() {
+        // 12 x 8 tiles
+        let picture = input().picture().unwrap();
+        let image = picture[..64].to_vec();
+        let mut rng = Rng::new(42);
+        let jigsaw = parse_input(&cut_jigsaw(&image, 10, &mut rng));
+        assert_eq!(jigsaw.layouts()[0], (8, 12));
+        // Long thin layouts take a while to rule out, so stop at the 8 we expect
+        let solutions = jigsaw.solve(8);
+        assert_eq!(solutions.len(), 8);
+        assert_eq!(
+            solutions
+                .iter()
+                .filter(|solution| solution.len() == 8)
+                .count(),
+            4
+        );
+        assert!(solutions
+            .iter()
+            .any(|solution| jigsaw.picture_of(solution) == image));
+        // A single row
+        let image = picture[..8].to_vec();
+        let jigsaw = parse_input(&cut_jigsaw(&image, 10, &mut rng));
+        assert!(jigsaw
+            .solve(usize::MAX)
+            .iter()
+            .any(|solution| jigsaw.picture_of(solution) == image));
+    }
+    #[test]
+    fn test_holes() {
+        // Without its middle tile, the example only fits in a 3x3 grid with a hole in the middle
+        let without_middle = EXAMPLE
+            .split("\n\n")
+            .filter(|tile| !tile.starts_with("Tile 1427:"))
+            .join("\n\n");
+        let jigsaw = parse_input(&without_middle);
+        assert!(jigsaw.solve(usize::MAX).is_empty());
+        let solutions = jigsaw.solve_in(3, 3, usize::MAX);
+        assert!(!solutions.is_empty());
+        assert!(solutions
+            .iter()
+            .all(|solution| holes(solution) == vec![(1, 1)]));
+        let picture = jigsaw.picture_of(&solutions[0]);
+        assert_eq!(picture.len(), 24);
+        assert!(picture[8..16]
+            .iter()
+            .all(|row| !row[8..16].iter().any(|cell| *cell)));
+        // Assembling falls back to the squarest grid with room for every tile
+        let assembled = jigsaw.assemble_jigsaw().unwrap();
+        assert_eq!(holes(&assembled), vec![(1, 1)]);
+        assert!(jigsaw
+            .to_pbm(ExportOptions::default())
+            .unwrap()
+            .starts_with("P1\n24 24\n"));
+        // The top and bottom rows don't touch without the middle one, and tiles must hang together
+        let without_middle_row = EXAMPLE
+            .split("\n\n")
+            .filter(|tile| {
+                !["Tile 2729:", "Tile 1427:", "Tile 2473:"]
+                    .iter()
+                    .any(|id| tile.starts_with(id))
+            })
+            .join("\n\n");
+        let jigsaw_rows = parse_input(&without_middle_row);
+        assert!(jigsaw_rows.solve_in(3, 3, usize::MAX).is_empty());
+        assert_eq!(jigsaw_rows.assemble_jigsaw(), None);
+        // Opposite corners share no edge
+        let corners = EXAMPLE
+            .split("\n\n")
+            .filter(|tile| tile.starts_with("Tile 1951:") || tile.starts_with("Tile 1171:"))
+            .join("\n\n");
+        let jigsaw_corners = parse_input(&corners);
+        assert_eq!(jigsaw_corners.assemble_jigsaw(), None);
+        assert_eq!(jigsaw_corners.to_pbm(ExportOptions::default()), None);
+        // Too few cells for all the tiles
+        assert!(jigsaw.solve_in(2, 3, usize::MAX).is_empty());
+        // A grid too big leaves holes around the edges
+        let solutions = jigsaw.solve_in(3, 4, 1);
+        assert_eq!(holes(&solutions[0]).len(), 4);
+    }
+    #[test]
+    fn test_missing_tiles() {
+        let full = Tile {
+            data: input().picture().unwrap(),
+        };
+        let tiles = include_str!("../input/2020/day20.txt")
+            .split_terminator("\n\n")
+            .collect::<Vec<_>>();
+        for dropped in [vec![0, 1], vec![5, 70, 100], vec![10, 11, 40, 90, 143]].iter() {
+            let jigsaw = parse_input(
+                &tiles
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| !dropped.contains(index))
+                    .map(|(_, tile)| tile)
+                    .join("\n\n"),
+            );
+            let assembled = jigsaw.assemble_jigsaw().unwrap();
+            assert_eq!((assembled.len(), assembled[0].len()), (12, 12));
+            let holes = holes(&assembled);
+            assert_eq!(holes.len(), dropped.len());
+            // The full picture, but for the missing tiles
+            let picture = jigsaw.picture_of(&assembled);
+            assert!((0..8).any(|perm| {
+                let oriented = full.with_permutation(perm).data;
+                (0..96).all(|row| {
+                    (0..96).all(|col| {
+                        holes.contains(&(row / 8, col / 8))
+                            || picture[row][col] == oriented[row][col]
+                    })
+                })
+            }));
+        }
+    }
     #[test]
     fn test_part1() {
         assert_eq!(140656720229539, part1(&input()))
@@ -4,9 +4,11 @@
 #![feature(iter_intersperse)]
 use aoc_runner_derive::aoc_lib;
 
+pub mod crt;
 pub mod point3d;
 pub mod point4d;
 pub mod pointnd;
+pub mod rng;
 
 pub mod day01;
 pub mod day02;
@@ -0,0 +1,23 @@
+/// xorshift64* pseudo random number generator.
+/// Reproducible from its seed, which is all we need to generate puzzles, so no need for a crate.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Scramble small seeds. The state must never be zero, or it stays zero forever
+        let state = seed ^ 0x9E37_79B9_7F4A_7C15;
+        Self(if state == 0 { 1 } else { state })
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    /// Uniformly distributed in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0, "Can't pick a number below 0");
+        (self.next_u64() % n as u64) as usize
+    }
+}